@@ -0,0 +1,13 @@
+use rocket::serde::json::Json;
+
+use crate::types::api::ResponseBody;
+
+#[catch(401)]
+pub fn unauthorized() -> Json<ResponseBody> {
+    Json(ResponseBody { message: format!("Unauthorized."), success: false, data: None })
+}
+
+#[catch(500)]
+pub fn internal_server_error() -> Json<ResponseBody> {
+    Json(ResponseBody { message: format!("There's an unexpected error."), success: false, data: None })
+}
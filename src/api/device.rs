@@ -1,7 +1,8 @@
+use mongodb::bson::DateTime;
 use rocket::{http, response::status, serde::json::Json, State};
 use serde::{Deserialize, Serialize};
 
-use crate::{db::Database, types::error::ErrorType};
+use crate::{db::Database, types::error::ErrorType, utils::env_or};
 
 
 #[derive(Serialize, Deserialize)]
@@ -17,6 +18,22 @@ pub struct DeviceConnectControllable {
     pub device_pass: String
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct TelemetryReading {
+    pub controllable_name: String,
+    //? Numbers, or booleans for binary sensors which are stored as 1 and 0
+    pub value: serde_json::Value,
+    //? Unix time in milliseconds
+    pub timestamp: Option<i64>
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeviceTelemetry {
+    pub device_key: String,
+    pub device_pass: String,
+    pub readings: Vec<TelemetryReading>
+}
+
 
 #[post("/device/initialization", data = "<body_data>")]
 pub async fn device_initialization(db: &State<Database>, body_data: Json<DeviceInitialization>) -> status::Custom<String> {
@@ -45,6 +62,32 @@ pub async fn device_initialization(db: &State<Database>, body_data: Json<DeviceI
     };
 }
 
+#[post("/device/heartbeat", data = "<body_data>")]
+pub async fn device_heartbeat(db: &State<Database>, body_data: Json<DeviceInitialization>) -> status::Custom<String> {
+    let device_key = &body_data.device_key;
+    let device_pass = &body_data.device_pass;
+
+    let device_data = match db.verify_device_key_pass(device_key, device_pass).await {
+        Ok(res) => res,
+        Err(err) => {
+            return match err {
+                ErrorType::DeviceNotFound(_) => status::Custom(http::Status::NotFound, format!("NOT FOUND")),
+                _ => status::Custom(http::Status::InternalServerError, format!("ERROR"))
+            };
+        }
+    };
+
+    match db.mark_device_online(&device_data.id, "heartbeat").await {
+        Ok(_) => status::Custom(http::Status::Ok, format!("OK")),
+        Err(err) => {
+            match err {
+                ErrorType::DeviceNotFound(_) => status::Custom(http::Status::NotFound, format!("NOT FOUND")),
+                _ => status::Custom(http::Status::InternalServerError, format!("ERROR"))
+            }
+        }
+    }
+}
+
 #[post("/device/get_controllable", data = "<body_data>")]
 pub async fn get_controllable(db: &State<Database>, body_data: Json<DeviceConnectControllable>) -> status::Custom<String>  {
     let device_key = &body_data.device_key;
@@ -64,8 +107,8 @@ pub async fn get_controllable(db: &State<Database>, body_data: Json<DeviceConnec
         }
     };
     
-    // Get controllable data
-    let controllable_data = db.get_controllable(controllable_name).await;
+    // Get controllable data of this device
+    let controllable_data = db.get_controllable(&device_data.id, controllable_name).await;
 
     let controllable_data = match controllable_data {
         Ok(res) => res,
@@ -77,17 +120,141 @@ pub async fn get_controllable(db: &State<Database>, body_data: Json<DeviceConnec
         }
     };
 
-    // Get user data
-    let user_data = db.get_user(&device_data.user_email).await;
-    let user_data = match user_data {
+    //? Kept in its original `topic,mqtt_user,mqtt_pass` shape for deployed firmware, the broker credentials are now the
+    //? device's own key and pass instead of the owner's. Firmware taking commands should move to the v2 endpoint.
+    status::Custom(http::Status::Ok, format!("{},{},{}", controllable_data.topic_name, device_key, device_pass))
+}
+
+//? Same lookup as `get_controllable`, answered as JSON with the command topic the device has to subscribe to
+#[post("/device/v2/get_controllable", data = "<body_data>")]
+pub async fn get_controllable_v2(db: &State<Database>, body_data: Json<DeviceConnectControllable>) -> status::Custom<String> {
+    let device_key = &body_data.device_key;
+    let device_pass = &body_data.device_pass;
+    let controllable_name = &body_data.controllable_name;
+
+    let device_data = match db.verify_device_key_pass(device_key, device_pass).await {
         Ok(res) => res,
         Err(err) => {
             return match err {
-                ErrorType::UserNotFound(_) => status::Custom(http::Status::NotFound, format!("User not found.")),
-                _ => status::Custom(http::Status::InternalServerError, format!("There's an error.")),
-            }
+                ErrorType::DeviceNotFound(_) => status::Custom(http::Status::NotFound, format!("Device not found.")),
+                _ => status::Custom(http::Status::InternalServerError, format!("There's an error."))
+            };
         }
     };
-    
-    status::Custom(http::Status::Ok, format!("{},{},{}", controllable_data.topic_name, user_data.mqtt_user, user_data.mqtt_pass))
-}
\ No newline at end of file
+
+    let controllable_data = match db.get_controllable(&device_data.id, controllable_name).await {
+        Ok(res) => res,
+        Err(err) => {
+            return match err {
+                ErrorType::ControllableNotFound(_) => status::Custom(http::Status::NotFound, format!("Controllable not found.")),
+                _ => status::Custom(http::Status::InternalServerError, format!("There's an error."))
+            };
+        }
+    };
+
+    status::Custom(http::Status::Ok, serde_json::json!({
+        "state_topic": controllable_data.topic_name,
+        "command_topic": controllable_data.command_topic(),
+        "mqtt_user": device_key,
+        "mqtt_pass": device_pass
+    }).to_string())
+}
+
+//? Lets firmware set itself up from the controllable's config, the body is the config as JSON
+#[post("/device/get_controllable_config", data = "<body_data>")]
+pub async fn get_controllable_config(db: &State<Database>, body_data: Json<DeviceConnectControllable>) -> status::Custom<String> {
+    let device_key = &body_data.device_key;
+    let device_pass = &body_data.device_pass;
+    let controllable_name = &body_data.controllable_name;
+
+    let device_data = match db.verify_device_key_pass(device_key, device_pass).await {
+        Ok(res) => res,
+        Err(err) => {
+            return match err {
+                ErrorType::DeviceNotFound(_) => status::Custom(http::Status::NotFound, format!("Device not found.")),
+                _ => status::Custom(http::Status::InternalServerError, format!("There's an error."))
+            };
+        }
+    };
+
+    let controllable_data = match db.get_controllable(&device_data.id, controllable_name).await {
+        Ok(res) => res,
+        Err(err) => {
+            return match err {
+                ErrorType::ControllableNotFound(_) => status::Custom(http::Status::NotFound, format!("Controllable not found.")),
+                _ => status::Custom(http::Status::InternalServerError, format!("There's an error."))
+            };
+        }
+    };
+
+    let controllable_config = controllable_data.config();
+    let mut config_json: serde_json::Value = match serde_json::to_value(&controllable_config) {
+        Ok(res) => res,
+        Err(_) => return status::Custom(http::Status::InternalServerError, format!("There's an error."))
+    };
+
+    //? Thermostat and temperature configs already use `unit` for the scale, so the display symbol goes next to it
+    if let Some(config_fields) = config_json.as_object_mut() {
+        config_fields.insert(String::from("unit_symbol"), serde_json::json!(controllable_config.unit()));
+    }
+
+    status::Custom(http::Status::Ok, config_json.to_string())
+}
+
+#[post("/device/telemetry", data = "<body_data>")]
+pub async fn device_telemetry(db: &State<Database>, body_data: Json<DeviceTelemetry>) -> status::Custom<String> {
+    let device_key = &body_data.device_key;
+    let device_pass = &body_data.device_pass;
+
+    let max_batch_size: usize = env_or("TELEMETRY_MAX_BATCH_SIZE", 500);
+    if body_data.readings.is_empty() || body_data.readings.len() > max_batch_size {
+        return status::Custom(http::Status::BadRequest, format!("A batch must have 1 to {} readings.", max_batch_size));
+    }
+
+    let device_data = match db.verify_device_key_pass(device_key, device_pass).await {
+        Ok(res) => res,
+        Err(err) => {
+            return match err {
+                ErrorType::DeviceNotFound(_) => status::Custom(http::Status::NotFound, format!("Device not found.")),
+                _ => status::Custom(http::Status::InternalServerError, format!("There's an error."))
+            };
+        }
+    };
+
+    //? Readings without a timestamp are taken as "now", ones from the future are rejected to keep the series sane
+    let now: DateTime = DateTime::now();
+    let max_clock_skew_millis: i64 = env_or("TELEMETRY_MAX_CLOCK_SKEW_SECONDS", 300) * 1000;
+    let mut readings: Vec<(String, f64, DateTime)> = Vec::with_capacity(body_data.readings.len());
+
+    for reading in &body_data.readings {
+        let value: f64 = match &reading.value {
+            serde_json::Value::Number(number) => match number.as_f64() {
+                Some(res) if res.is_finite() => res,
+                _ => return status::Custom(http::Status::BadRequest, format!("Reading value is not valid."))
+            },
+            serde_json::Value::Bool(state) => if *state { 1.0 } else { 0.0 },
+            _ => return status::Custom(http::Status::BadRequest, format!("Reading value is not valid."))
+        };
+
+        let timestamp: DateTime = match reading.timestamp {
+            Some(timestamp) if timestamp > now.timestamp_millis() + max_clock_skew_millis => {
+                return status::Custom(http::Status::BadRequest, format!("Reading timestamp is in the future."));
+            },
+            Some(timestamp) => DateTime::from_millis(timestamp),
+            None => now
+        };
+
+        readings.push((reading.controllable_name.clone(), value, timestamp));
+    }
+
+    match db.insert_telemetry(&device_data, readings).await {
+        Ok(inserted_count) => status::Custom(http::Status::Ok, format!("{}", inserted_count)),
+        Err(err) => {
+            match err {
+                ErrorType::ControllableNotFound(Some(controllable_name)) => status::Custom(http::Status::NotFound, format!("Controllable {} not found.", controllable_name)),
+                ErrorType::ControllableNotFound(None) => status::Custom(http::Status::NotFound, format!("Controllable not found.")),
+                _ => status::Custom(http::Status::InternalServerError, format!("There's an error."))
+            }
+        }
+    }
+}
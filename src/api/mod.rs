@@ -1,2 +1,4 @@
 pub mod user;
-pub mod device;
\ No newline at end of file
+pub mod device;
+pub mod catcher;
+pub mod mqtt;
\ No newline at end of file
@@ -0,0 +1,241 @@
+//? Webhooks for the broker's HTTP auth plugin (mosquitto-go-auth `http` backend or EMQX HTTP authn/authz).
+//? Access is granted with `200` and refused with `403`, the body carries the same answer for plugins reading JSON.
+//? Every call must carry the shared `MQTT_WEBHOOK_SECRET`, anything else is refused with `401`.
+//? Principals are either a user (`mqtt_user`/`mqtt_pass`) or a single device (`device_key`/`device_pass`).
+use rocket::{http, response::status, serde::json::Json, State};
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+
+use crate::{db::Database, middlewares::security::MqttWebhookSecret, types::{db_model::{ControllableDirection, COMMAND_TOPIC_SUFFIX}, error::ErrorType}};
+
+
+#[derive(Serialize, Deserialize)]
+pub struct MqttAuthBody {
+    pub username: String,
+    pub password: String
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MqttSuperuserBody {
+    pub username: String
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MqttAclBody {
+    pub username: String,
+    pub topic: String,
+    //? mosquitto-go-auth sends `acc` (1 read, 2 write, 3 readwrite, 4 subscribe), EMQX sends `action`
+    pub acc: Option<i32>,
+    pub action: Option<String>
+}
+
+#[derive(Serialize)]
+pub struct MqttAuthResponse {
+    pub result: String,
+    pub is_superuser: bool,
+    #[serde(rename = "Ok")]
+    pub ok: bool,
+    #[serde(rename = "Error")]
+    pub error: String
+}
+
+#[derive(Debug, PartialEq)]
+enum MqttAccess {
+    Read,
+    Write,
+    ReadWrite
+}
+
+impl MqttAccess {
+    fn from_body(body_data: &MqttAclBody) -> Option<Self> {
+        match (body_data.acc, body_data.action.as_deref()) {
+            (Some(1), _) | (Some(4), _) | (None, Some("subscribe")) => Some(Self::Read),
+            (Some(2), _) | (None, Some("publish")) => Some(Self::Write),
+            (Some(3), _) => Some(Self::ReadWrite),
+            _ => None
+        }
+    }
+
+    fn writes(&self) -> bool {
+        *self != Self::Read
+    }
+
+    //? Users may read every controllable they own but only write commands, and sensors take none
+    fn allows_user(&self, topic: &str, direction: &ControllableDirection) -> bool {
+        !self.writes() || (topic.ends_with(COMMAND_TOPIC_SUFFIX) && *direction != ControllableDirection::Input)
+    }
+
+    //? Devices publish state and read commands, never the other way around
+    fn allows_device(&self, topic: &str) -> bool {
+        !self.writes() || !topic.ends_with(COMMAND_TOPIC_SUFFIX)
+    }
+}
+
+fn allow(is_superuser: bool) -> status::Custom<Json<MqttAuthResponse>> {
+    status::Custom(http::Status::Ok, Json(MqttAuthResponse { result: format!("allow"), is_superuser, ok: true, error: format!("") }))
+}
+
+fn deny(reason: &str) -> status::Custom<Json<MqttAuthResponse>> {
+    status::Custom(http::Status::Forbidden, Json(MqttAuthResponse { result: format!("deny"), is_superuser: false, ok: false, error: reason.to_string() }))
+}
+
+fn is_superuser(username: &str) -> bool {
+    match std::env::var("MQTT_SUPERUSER") {
+        Ok(superuser) => !superuser.is_empty() && superuser == username,
+        Err(_) => false
+    }
+}
+
+fn verify_superuser_pass(password: &str) -> bool {
+    match std::env::var("MQTT_SUPERUSER_PASS") {
+        Ok(superuser_pass) => !superuser_pass.is_empty() && bool::from(superuser_pass.as_bytes().ct_eq(password.as_bytes())),
+        Err(_) => false
+    }
+}
+
+
+#[post("/mqtt/auth", data = "<body_data>")]
+pub async fn mqtt_auth(_webhook_secret: MqttWebhookSecret, db: &State<Database>, body_data: Json<MqttAuthBody>) -> status::Custom<Json<MqttAuthResponse>> {
+    let username = &body_data.username;
+    let password = &body_data.password;
+
+    if is_superuser(username) {
+        return match verify_superuser_pass(password) {
+            true => allow(true),
+            false => deny("Wrong credentials.")
+        };
+    }
+
+    match db.verify_mqtt_credentials(username, password).await {
+        Ok(_) => return allow(false),
+        Err(ErrorType::UserNotFound(_)) | Err(ErrorType::Unauthorized(_)) => (),
+        Err(_) => return status::Custom(http::Status::InternalServerError, Json(MqttAuthResponse { result: format!("deny"), is_superuser: false, ok: false, error: format!("There's an error.") }))
+    };
+
+    // Not a user, try it as a device
+    match db.verify_device_key_pass(username, password).await {
+        Ok(_) => allow(false),
+        Err(ErrorType::DeviceNotFound(_)) => deny("Wrong credentials."),
+        Err(_) => status::Custom(http::Status::InternalServerError, Json(MqttAuthResponse { result: format!("deny"), is_superuser: false, ok: false, error: format!("There's an error.") }))
+    }
+}
+
+#[post("/mqtt/superuser", data = "<body_data>")]
+pub async fn mqtt_superuser(_webhook_secret: MqttWebhookSecret, body_data: Json<MqttSuperuserBody>) -> status::Custom<Json<MqttAuthResponse>> {
+    match is_superuser(&body_data.username) {
+        true => allow(true),
+        false => deny("Not a superuser.")
+    }
+}
+
+#[post("/mqtt/acl", data = "<body_data>")]
+pub async fn mqtt_acl(_webhook_secret: MqttWebhookSecret, db: &State<Database>, body_data: Json<MqttAclBody>) -> status::Custom<Json<MqttAuthResponse>> {
+    let username = &body_data.username;
+    let topic = &body_data.topic;
+
+    if is_superuser(username) {
+        return allow(true);
+    }
+
+    let access: MqttAccess = match MqttAccess::from_body(&body_data) {
+        Some(res) => res,
+        None => return deny("Unknown access.")
+    };
+
+    // Get the controllable behind the topic, wildcards never match
+    let controllable_data = match db.get_controllable_by_topic(topic).await {
+        Ok(res) => res,
+        Err(ErrorType::ControllableNotFound(_)) => return deny("Topic not allowed."),
+        Err(_) => return status::Custom(http::Status::InternalServerError, Json(MqttAuthResponse { result: format!("deny"), is_superuser: false, ok: false, error: format!("There's an error.") }))
+    };
+
+    //? The device is the only one allowed to publish state, so anything on a state topic is a sign of life
+    match db.get_user_by_mqtt_user(username).await {
+        Ok(user_data) => {
+            if controllable_data.owner_id != user_data.id {
+                return deny("Topic not allowed.");
+            }
+
+            return match access.allows_user(topic, &controllable_data.direction) {
+                true => allow(false),
+                false => deny("Access not allowed.")
+            };
+        },
+        Err(ErrorType::UserNotFound(_)) => (),
+        Err(_) => return status::Custom(http::Status::InternalServerError, Json(MqttAuthResponse { result: format!("deny"), is_superuser: false, ok: false, error: format!("There's an error.") }))
+    };
+
+    // Devices may only access their own controllables, publishing state and reading commands
+    match db.get_device_by_key(username).await {
+        Ok(device_data) if controllable_data.device_id == device_data.id => match access.allows_device(topic) {
+            true => allow(false),
+            false => deny("Access not allowed.")
+        },
+        Ok(_) => deny("Topic not allowed."),
+        Err(ErrorType::DeviceNotFound(_)) => deny("Unknown principal."),
+        Err(_) => status::Custom(http::Status::InternalServerError, Json(MqttAuthResponse { result: format!("deny"), is_superuser: false, ok: false, error: format!("There's an error.") }))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acl_body(acc: Option<i32>, action: Option<&str>) -> MqttAclBody {
+        MqttAclBody { username: String::from("user"), topic: String::from("home/lamp"), acc, action: action.map(String::from) }
+    }
+
+    #[test]
+    fn reads_access_from_either_plugin() {
+        assert_eq!(MqttAccess::from_body(&acl_body(Some(1), None)), Some(MqttAccess::Read));
+        assert_eq!(MqttAccess::from_body(&acl_body(Some(2), None)), Some(MqttAccess::Write));
+        assert_eq!(MqttAccess::from_body(&acl_body(Some(3), None)), Some(MqttAccess::ReadWrite));
+        assert_eq!(MqttAccess::from_body(&acl_body(Some(4), None)), Some(MqttAccess::Read));
+        assert_eq!(MqttAccess::from_body(&acl_body(None, Some("subscribe"))), Some(MqttAccess::Read));
+        assert_eq!(MqttAccess::from_body(&acl_body(None, Some("publish"))), Some(MqttAccess::Write));
+
+        //? `acc` wins when a plugin sends both
+        assert_eq!(MqttAccess::from_body(&acl_body(Some(1), Some("publish"))), Some(MqttAccess::Read));
+    }
+
+    #[test]
+    fn refuses_unknown_access() {
+        assert_eq!(MqttAccess::from_body(&acl_body(None, None)), None);
+        assert_eq!(MqttAccess::from_body(&acl_body(Some(0), None)), None);
+        assert_eq!(MqttAccess::from_body(&acl_body(Some(5), Some("subscribe"))), None);
+        assert_eq!(MqttAccess::from_body(&acl_body(None, Some("all"))), None);
+    }
+
+    #[test]
+    fn users_only_write_commands_to_outputs() {
+        let output = ControllableDirection::Output;
+        let input = ControllableDirection::Input;
+
+        assert!(MqttAccess::Read.allows_user("home/lamp", &output));
+        assert!(MqttAccess::Read.allows_user("home/lamp/set", &output));
+        assert!(MqttAccess::Read.allows_user("home/thermometer", &input));
+
+        assert!(MqttAccess::Write.allows_user("home/lamp/set", &output));
+        assert!(MqttAccess::ReadWrite.allows_user("home/lamp/set", &output));
+        assert!(!MqttAccess::Write.allows_user("home/lamp", &output));
+        assert!(!MqttAccess::ReadWrite.allows_user("home/lamp", &output));
+        assert!(!MqttAccess::Write.allows_user("home/thermometer/set", &input));
+
+        //? Only the suffix counts, `/set` elsewhere in the topic is a state topic
+        assert!(!MqttAccess::Write.allows_user("home/set/lamp", &output));
+        assert!(!MqttAccess::Write.allows_user("home/lampset", &output));
+    }
+
+    #[test]
+    fn devices_only_write_state() {
+        assert!(MqttAccess::Read.allows_device("home/lamp"));
+        assert!(MqttAccess::Read.allows_device("home/lamp/set"));
+
+        assert!(MqttAccess::Write.allows_device("home/lamp"));
+        assert!(MqttAccess::ReadWrite.allows_device("home/lamp"));
+        assert!(MqttAccess::Write.allows_device("home/set/lamp"));
+        assert!(!MqttAccess::Write.allows_device("home/lamp/set"));
+        assert!(!MqttAccess::ReadWrite.allows_device("home/lamp/set"));
+    }
+}
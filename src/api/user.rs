@@ -1,7 +1,6 @@
-use crate::{db::Database, middlewares::security::ApiKey, types::{api::{ResponseBody, ResponseBodyType}, db_model::{ControllableCategory, LoginOTPTable, RegistrationTable, User}, error::ErrorType}, utils::{self, create_user_token, sends_email, verify_user_token_from_cookie}};
-use lettre::{message::Mailbox, transport::smtp::authentication::Credentials, Message, SmtpTransport, Transport};
-use mongodb::bson::oid::ObjectId;
-use rocket::{http::{self, private::cookie, Cookie, CookieJar}, response::status, serde::json::Json, State};
+use crate::{db::Database, tasks::mqtt::MqttClient, middlewares::security::{ApiKey, AuthenticatedUser}, types::{api::{DeviceData, ResponseBody, ResponseBodyType, UserData}, db_model::{DeviceStatus, RetentionPolicy, HistoryBucketSize, CommandTable, ControllableCategory, ControllableConfig, ControllableDirection, LoginOTPTable, RegistrationTable, User}, error::ErrorType}, utils::{self, remove_session_cookies, sends_email, set_session_cookies, start_user_session, verify_user_token_from_cookie}};
+use mongodb::bson::{oid::ObjectId, DateTime};
+use rocket::{http::{self, CookieJar}, response::status, serde::json::Json, State};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize)]
@@ -9,6 +8,11 @@ pub struct UserRegistrationBody {
     pub email: String
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct ResendConfirmationBody {
+    pub email: String
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct ConfirmRegistrationBody {
     pub id: ObjectId,
@@ -40,16 +44,85 @@ pub struct OTPLoginVerifyBody {
     pub otp: String
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct PasswordResetRequestBody {
+    pub email: String
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PasswordResetConfirmBody {
+    pub token: String,
+    pub new_password: String
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UpdateProfileBody {
+    pub username: String
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChangePasswordBody {
+    pub current_password: String,
+    pub new_password: String
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChangeEmailRequestBody {
+    pub new_email: String,
+    pub password: String
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChangeEmailConfirmBody {
+    pub token: String
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeleteAccountBody {
+    pub password: String
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct CreateDeviceBody {
     pub device_name: String
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct UpdateDeviceBody {
+    pub device_name: String
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RotateDeviceCredentialsBody {
+    pub grace_period_seconds: Option<u64>
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct CreateControllableBody {
     pub device_id: String,
     pub controllable_name: String,
-    pub controllable_category: String
+    pub controllable_category: String,
+    pub controllable_config: Option<ControllableConfig>
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UpdateControllableBody {
+    pub controllable_name: Option<String>,
+    pub controllable_category: Option<String>,
+    pub controllable_config: Option<ControllableConfig>
+}
+
+//? `null` drops the custom policy and falls back to the next level up
+#[derive(Serialize, Deserialize)]
+pub struct UpdateRetentionBody {
+    pub retention: Option<RetentionPolicy>
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SendCommandBody {
+    pub value: serde_json::Value,
+    pub qos: Option<u8>,
+    pub retain: Option<bool>
 }
 
 
@@ -81,67 +154,43 @@ pub async fn user_registration(_api_key: ApiKey, db: &State<Database>, body_data
 
 
     //? Send the token to targetted email
-    let email_user: String = std::env::var("EMAIL_USER").expect("Please, define 'EMAIL_USER' in your .env");
-    let email_pass: String = std::env::var("EMAIL_PASS").expect("Please, define 'EMAIL_PASS' in your .env");
-    
-    let email_from: Mailbox = match format!("ROVI Project <{}>", email_user).parse::<Mailbox>() {
-        Ok(res) => res,
-        Err(err) => {
-            println!("There's an error when trying to build email_from data. Error: {}", err.to_string());
-            return status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an error when sending email!"), success: false, data: None }));
-        }
+    match sends_email(user_email, "Account Confirmation", format!("Hi there, Thank you for signing up to ROVI Project! Please use token below to proceed:<br /><b>TOKEN:[{}]</b>", registration_data.confirmation_token).as_str()) {
+        Ok(_) => (),
+        Err(_) => return status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an error when trying to send email"), success: false, data: None }))
     };
 
-    let email_to: Mailbox = match user_email.parse::<Mailbox>() {
-        Ok(res) => res,
-        Err(err) => {
-            println!("There's an error when trying to build email_to data. Error: {}", err.to_string());
-            return status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an error when sending email!"), success: false, data: None }));
-        }
-    };
-    
-    let email = match Message::builder()
-        .from(email_from)
-        .to(email_to)
-        .subject("Account Confirmation")
-        .body(format!("Hi there, Thank you for signing up to ROVI Project! Please use token below to proceed:<br /><b>TOKEN:[{}]</b>", registration_data.confirmation_token)) {
-            Ok(res) => res,
-            Err(err) => {
-                println!("There's an error when trying to build message data. Error: {}", err);
-                return status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an error when sending email!"), success: false, data: None }));
-            }
-        };
+    //? Success
+    status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully sent email confirmation to {}!", user_email.as_str()), success: true, data: Some(ResponseBodyType::UserRegistration { id: registration_data.id.to_string() }) }))
+}
 
-    let creds = Credentials::new(
-        email_user.to_string(),
-        email_pass.to_string(),
-    );
-    
-    let mailer = match SmtpTransport::relay("smtp.gmail.com") {
-        Ok(res) => res,
-        Err(err) => {
-            println!("There's an error when trying to build SMTP Transport. Error: {}", err);
-            return status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an error when sending email!"), success: false, data: None }));
-        }
-    }
-        .credentials(creds)
-        .build();
 
-    match mailer.send(&email) {
-        Ok(_) => (),
+#[post("/user/resend_confirmation", data = "<body_data>")]
+pub async fn resend_confirmation(_api_key: ApiKey, db: &State<Database>, body_data: Json<ResendConfirmationBody>) -> status::Custom<Json<ResponseBody>> {
+    //? Get the required data
+    let user_email = &body_data.email;
+
+    //? Renew the tokens of the pending registration
+    let registration_data: RegistrationTable = match db.refresh_registration(user_email).await {
+        Ok(result) => result,
         Err(error) => {
-            println!("There's an error when trying to send data: {error}");
-            return status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an error when sending email!"), success: false, data: None }))
-        },
+            match error {
+                ErrorType::RegistrationNotFound(_) => {
+                    return status::Custom(http::Status::NotFound, Json(ResponseBody { message: format!("There's no pending registration for this email."), success: false, data: None }));
+                },
+                _ => ()
+            }
+            return status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("Sorry, there's an unexpected error"), success: false, data: None }));
+        }
     };
 
+    //? Send the new token to targetted email
     match sends_email(user_email, "Account Confirmation", format!("Hi there, Thank you for signing up to ROVI Project! Please use token below to proceed:<br /><b>TOKEN:[{}]</b>", registration_data.confirmation_token).as_str()) {
         Ok(_) => (),
         Err(_) => return status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an error when trying to send email"), success: false, data: None }))
     };
 
     //? Success
-    status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully sent email confirmation to {}!", user_email.as_str()), success: true, data: Some(ResponseBodyType::UserRegistration { id: registration_data.id.to_string() }) }))
+    status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully resent email confirmation to {}!", user_email.as_str()), success: true, data: Some(ResponseBodyType::UserRegistration { id: registration_data.id.to_string() }) }))
 }
 
 
@@ -162,6 +211,9 @@ pub async fn confirm_registration(_api_key: ApiKey, db: &State<Database>, body_d
                 ErrorType::Unauthorized(_) => {
                     return status::Custom(http::Status::Unauthorized, Json(ResponseBody { message: format!("Wrong token."), success: false, data: None }))
                 },
+                ErrorType::TokenExpired(_) => {
+                    return status::Custom(http::Status::Unauthorized, Json(ResponseBody { message: format!("Token expired, please request a new one."), success: false, data: None }))
+                },
                 _ => ()
             };
             return status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an unexpected error."), success: false, data: None }))
@@ -185,8 +237,10 @@ pub async fn setup_registration(_api_key: ApiKey, db: &State<Database>, body_dat
     let result = db.setup_account(target_id, setup_token, username, password).await;
     match result {
         Ok(user_data) => {
-            cookies.add(Cookie::new("user_token", create_user_token(user_data.email.as_str())));
-            status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully register!"), success: true, data: Some(ResponseBodyType::UserSetup { user_data }) }))
+            if start_user_session(db, cookies, &user_data).await.is_err() {
+                return status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an error when trying to create session."), success: false, data: None }));
+            }
+            status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully register!"), success: true, data: Some(ResponseBodyType::UserSetup { mqtt_pass: user_data.mqtt_pass.clone(), user_data: user_data.into() }) }))
         },
         Err(err) => {
             match err {
@@ -196,6 +250,9 @@ pub async fn setup_registration(_api_key: ApiKey, db: &State<Database>, body_dat
                 ErrorType::Unauthorized(_) => {
                     return status::Custom(http::Status::Unauthorized, Json(ResponseBody { message: format!("Wrong token."), success: false, data: None }))
                 },
+                ErrorType::TokenExpired(_) => {
+                    return status::Custom(http::Status::Unauthorized, Json(ResponseBody { message: format!("Token expired, please request a new one."), success: false, data: None }))
+                },
                 ErrorType::DuplicatesFound(_) => {
                     return status::Custom(http::Status::Conflict, Json(ResponseBody { message: format!("Duplicates found."), success: false, data: None }))
                 },
@@ -218,8 +275,10 @@ pub async fn user_password_login(_api_key: ApiKey, db: &State<Database>, body_da
 
     match login_result {
         Ok(res) => {
-            cookies.add(Cookie::new("user_token", create_user_token(res.email.as_str())));
-            return status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully login."), success: true, data: Some(ResponseBodyType::UserLogin { user_data: res }) }));
+            if start_user_session(db, cookies, &res).await.is_err() {
+                return status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an error when trying to create session."), success: false, data: None }));
+            }
+            return status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully login."), success: true, data: Some(ResponseBodyType::UserLogin { user_data: res.into() }) }));
         },
         Err(err) => {
             match err {
@@ -248,6 +307,7 @@ pub async fn user_otp_login(_api_key: ApiKey, db: &State<Database>, body_data: J
         Err(err) => {
             match err {
                 ErrorType::DuplicatesFound(_) => return status::Custom(http::Status::Conflict, Json(ResponseBody { message: format!("Duplicated data found."), success: false, data: None })),
+                ErrorType::TooManyAttempts(_) => return status::Custom(http::Status::TooManyRequests, Json(ResponseBody { message: format!("Too many failed attempts, please try again later."), success: false, data: None })),
                 _ => return status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an error when trying to create otp code"), success: false, data: None }))
             }
         }
@@ -277,91 +337,681 @@ pub async fn user_otp_verify(_api_key: ApiKey, db: &State<Database>, body_data:
         Err(err) => {
             match err {
                 ErrorType::Unauthorized(_) => return status::Custom(http::Status::Unauthorized, Json(ResponseBody { message: format!("Unauthorized token"), success: false, data: None })),
+                ErrorType::TokenExpired(_) => return status::Custom(http::Status::Unauthorized, Json(ResponseBody { message: format!("Token expired, please request a new one"), success: false, data: None })),
+                ErrorType::TooManyAttempts(_) => return status::Custom(http::Status::TooManyRequests, Json(ResponseBody { message: format!("Too many failed attempts, please try again later."), success: false, data: None })),
                 _ => return status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an error when checking the token"), success: false, data: None }))
             }
         }
     };
 
-    //? Create user token
-    cookies.add(Cookie::new("user_token", create_user_token(email)));
+    //? Create user session
+    let user_data: User = match db.get_user(email).await {
+        Ok(res) => res,
+        Err(err) => {
+            return match err {
+                ErrorType::UserNotFound(_) => status::Custom(http::Status::NotFound, Json(ResponseBody { message: format!("User not found."), success: false, data: None })),
+                _ => status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an unexpected error."), success: false, data: None }))
+            };
+        }
+    };
+
+    if start_user_session(db, cookies, &user_data).await.is_err() {
+        return status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an error when trying to create session."), success: false, data: None }));
+    }
     status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Email verified"), success: true, data: None }))
 }
 
 
-#[get("/user/get")]
-pub async fn user_get(_api_key: ApiKey, db: &State<Database>, cookies: &CookieJar<'_>) -> status::Custom<Json<ResponseBody>> {
-    //? Fetch user's email from user_token cookie!
-    let user_email: String = match verify_user_token_from_cookie(cookies) {
-        Ok(res) => res.clone(),
-        Err(_) => {
+#[post("/user/refresh")]
+pub async fn user_refresh(_api_key: ApiKey, db: &State<Database>, cookies: &CookieJar<'_>) -> status::Custom<Json<ResponseBody>> {
+    //? Get the refresh token
+    let refresh_token: String = match cookies.get("refresh_token") {
+        Some(res) => res.value().to_string(),
+        None => {
             return status::Custom(http::Status::Unauthorized, Json(ResponseBody { message: format!("Unauthorized."), success: false, data: None }));
         }
     };
 
-    
-    //? Get user data based on the user email that we've just got! :D
-    let user_data: User = match db.get_user(user_email.as_str()).await {
+    //? Exchange it with a new pair of tokens
+    let (session, new_refresh_token) = match db.rotate_session(&refresh_token).await {
         Ok(res) => res,
+        Err(err) => {
+            remove_session_cookies(cookies);
+            return match err {
+                ErrorType::Unauthorized(_) | ErrorType::TokenExpired(_) => status::Custom(http::Status::Unauthorized, Json(ResponseBody { message: format!("Unauthorized."), success: false, data: None })),
+                _ => status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an unexpected error."), success: false, data: None }))
+            };
+        }
+    };
+
+    //? Make sure the account still exists before handing out a new access token
+    if let Err(err) = db.get_user_by_id(&session.user_id).await {
+        return match err {
+            ErrorType::UserNotFound(_) => status::Custom(http::Status::Unauthorized, Json(ResponseBody { message: format!("Unauthorized."), success: false, data: None })),
+            _ => status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an unexpected error."), success: false, data: None }))
+        };
+    }
+
+    set_session_cookies(cookies, &session, new_refresh_token);
+    status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully refresh session."), success: true, data: None }))
+}
+
+
+#[post("/user/logout")]
+pub async fn user_logout(_api_key: ApiKey, db: &State<Database>, cookies: &CookieJar<'_>) -> status::Custom<Json<ResponseBody>> {
+    //? Find the session from the refresh token when a client sends it, browsers only send it to the refresh route
+    //? so for them the access token identifies the session
+    let session_family_id: Option<ObjectId> = match cookies.get("refresh_token") {
+        Some(refresh_token) => db.get_session_family_id(refresh_token.value()).await.ok(),
+        None => None
+    };
+
+    let session_family_id: Option<ObjectId> = match session_family_id {
+        Some(res) => Some(res),
+        None => match verify_user_token_from_cookie(db, cookies).await {
+            Ok(claims) => ObjectId::parse_str(&claims.sid).ok(),
+            Err(_) => None
+        }
+    };
+
+    remove_session_cookies(cookies);
+
+    let session_family_id: ObjectId = match session_family_id {
+        Some(res) => res,
+        None => {
+            return status::Custom(http::Status::Unauthorized, Json(ResponseBody { message: format!("Unauthorized."), success: false, data: None }));
+        }
+    };
+
+    match db.revoke_session_family(&session_family_id).await {
+        Ok(_) => status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully logout."), success: true, data: None })),
+        Err(_) => status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an unexpected error."), success: false, data: None }))
+    }
+}
+
+
+#[post("/user/logout_all")]
+pub async fn user_logout_all(_api_key: ApiKey, user: &AuthenticatedUser, db: &State<Database>, cookies: &CookieJar<'_>) -> status::Custom<Json<ResponseBody>> {
+    //? Revoke every session of the user, including this one
+    remove_session_cookies(cookies);
+    match db.revoke_user_sessions(&user.user.id).await {
+        Ok(_) => status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully logout from all sessions."), success: true, data: None })),
+        Err(_) => status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an unexpected error."), success: false, data: None }))
+    }
+}
+
+
+#[post("/user/password_reset/request", data = "<body_data>")]
+pub async fn password_reset_request(_api_key: ApiKey, db: &State<Database>, body_data: Json<PasswordResetRequestBody>) -> status::Custom<Json<ResponseBody>> {
+    //? Get the required data
+    let user_email = &body_data.email;
+    let success_response = status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("If the email is registered, a password reset token has been sent to it."), success: true, data: None }));
+
+    //? Don't tell the caller whether the email is registered or not
+    let user_data: User = match db.get_user(user_email).await {
+        Ok(res) => res,
+        Err(ErrorType::UserNotFound(_)) => return success_response,
+        Err(_) => return status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an unexpected error."), success: false, data: None }))
+    };
+
+    //? Failures past this point only happen for registered emails, they're logged but answered like everything else
+    //? Generate and store token
+    let (_, reset_token) = match db.create_password_reset(&user_data.id).await {
+        Ok(res) => res,
+        Err(_) => {
+            println!("There's an error when trying to create password reset token for user {}", user_data.id);
+            return success_response;
+        }
+    };
+
+    //? Send the token to the gmail
+    if sends_email(user_email, "Password Reset", format!("Hi there, We received a request to reset your ROVI Project password. Please use token below to proceed:<br /><b>TOKEN:[{}]</b><br />If you didn't request this, you can ignore this email.", reset_token).as_str()).is_err() {
+        println!("There's an error when trying to send password reset email to user {}", user_data.id);
+    }
+
+    success_response
+}
+
+
+#[post("/user/password_reset/confirm", data = "<body_data>")]
+pub async fn password_reset_confirm(_api_key: ApiKey, db: &State<Database>, body_data: Json<PasswordResetConfirmBody>, cookies: &CookieJar<'_>) -> status::Custom<Json<ResponseBody>> {
+    //? Get the required data
+    let reset_token = &body_data.token;
+    let new_password = &body_data.new_password;
+
+    //? Change the password, every existing session is revoked along the way
+    match db.reset_password(reset_token, new_password).await {
+        Ok(_) => {
+            remove_session_cookies(cookies);
+            status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully reset password, please login again."), success: true, data: None }))
+        },
         Err(err) => {
             match err {
-                ErrorType::UserNotFound(_) => {
-                    return status::Custom(http::Status::NotFound, Json(ResponseBody { message: format!("User not found."), success: false, data: None }))
-                },
-                ErrorType::UnknownError(_) => {
-                    return status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an unexpected error."), success: false, data: None }))
-                },
-                _ => {
-                    return status::Custom(http::Status::BadRequest, Json(ResponseBody { message: format!("There's a request error."), success: false, data: None }))
-                }
+                ErrorType::Unauthorized(_) => status::Custom(http::Status::Unauthorized, Json(ResponseBody { message: format!("Wrong token."), success: false, data: None })),
+                ErrorType::TokenExpired(_) => status::Custom(http::Status::Unauthorized, Json(ResponseBody { message: format!("Token expired, please request a new one."), success: false, data: None })),
+                ErrorType::UserNotFound(_) => status::Custom(http::Status::NotFound, Json(ResponseBody { message: format!("User not found."), success: false, data: None })),
+                _ => status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an unexpected error."), success: false, data: None }))
             }
         }
-    };
+    }
+}
+
 
+#[get("/user/get")]
+pub async fn user_get(_api_key: ApiKey, user: &AuthenticatedUser) -> status::Custom<Json<ResponseBody>> {
+    //? User data is already loaded by the `AuthenticatedUser` guard
+    let user_data: UserData = user.user.clone().into();
 
     //? Return the user data that we've just got! :)
-    status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully get user data"), success: true, data: Some(ResponseBodyType::UserGet { user_data: user_data }) }))
+    status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully get user data"), success: true, data: Some(ResponseBodyType::UserGet { user_data }) }))
 }
 
-#[post("/user/create_device", data = "<body_data>")]
-pub async fn create_device(body_data: Json<CreateDeviceBody>, _api_key: ApiKey, db: &State<Database>, cookies: &CookieJar<'_>) -> status::Custom<Json<ResponseBody>> {
-    let user_email = match verify_user_token_from_cookie(cookies) {
-        Ok(email) => email,
-        Err(_) => {
-            return status::Custom(http::Status::Unauthorized, Json(ResponseBody { message: format!("Unauthorized."), success: false, data: None }));
+#[post("/user/update_profile", data = "<body_data>")]
+pub async fn update_profile(_api_key: ApiKey, user: &AuthenticatedUser, db: &State<Database>, body_data: Json<UpdateProfileBody>) -> status::Custom<Json<ResponseBody>> {
+    //? Get the required data
+    let username = &body_data.username;
+
+    match db.update_username(&user.user.id, username).await {
+        Ok(user_data) => status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully update profile!"), success: true, data: Some(ResponseBodyType::UserUpdate { user_data: user_data.into() }) })),
+        Err(err) => {
+            match err {
+                ErrorType::DuplicatesFound(_) => status::Custom(http::Status::Conflict, Json(ResponseBody { message: format!("Username is already taken."), success: false, data: None })),
+                ErrorType::UserNotFound(_) => status::Custom(http::Status::NotFound, Json(ResponseBody { message: format!("User not found."), success: false, data: None })),
+                _ => status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an unexpected error."), success: false, data: None }))
+            }
+        }
+    }
+}
+
+#[post("/user/update_retention", data = "<body_data>")]
+pub async fn update_user_retention(_api_key: ApiKey, user: &AuthenticatedUser, db: &State<Database>, body_data: Json<UpdateRetentionBody>) -> status::Custom<Json<ResponseBody>> {
+    match db.update_user_retention(&user.user.id, body_data.retention).await {
+        Ok(user_data) => status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully update retention!"), success: true, data: Some(ResponseBodyType::UserUpdate { user_data: user_data.into() }) })),
+        Err(err) => {
+            match err {
+                ErrorType::InvalidConfig(message) => status::Custom(http::Status::BadRequest, Json(ResponseBody { message: message.unwrap_or(format!("Retention is not valid.")), success: false, data: None })),
+                ErrorType::UserNotFound(_) => status::Custom(http::Status::NotFound, Json(ResponseBody { message: format!("User not found."), success: false, data: None })),
+                _ => status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an unexpected error."), success: false, data: None }))
+            }
         }
+    }
+}
+
+#[post("/user/change_password", data = "<body_data>")]
+pub async fn change_password(_api_key: ApiKey, user: &AuthenticatedUser, db: &State<Database>, body_data: Json<ChangePasswordBody>) -> status::Custom<Json<ResponseBody>> {
+    //? Get the required data
+    let current_password = &body_data.current_password;
+    let new_password = &body_data.new_password;
+
+    let session_family_id: ObjectId = match ObjectId::parse_str(&user.claims.sid) {
+        Ok(res) => res,
+        Err(_) => return status::Custom(http::Status::Unauthorized, Json(ResponseBody { message: format!("Unauthorized."), success: false, data: None }))
     };
-    
+
+    match db.change_password(&user.user, current_password, new_password, &session_family_id).await {
+        Ok(_) => status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully change password!"), success: true, data: None })),
+        Err(err) => {
+            match err {
+                ErrorType::Unauthorized(_) => status::Custom(http::Status::Unauthorized, Json(ResponseBody { message: format!("Wrong password."), success: false, data: None })),
+                _ => status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an unexpected error."), success: false, data: None }))
+            }
+        }
+    }
+}
+
+
+#[post("/user/change_email/request", data = "<body_data>")]
+pub async fn change_email_request(_api_key: ApiKey, user: &AuthenticatedUser, db: &State<Database>, body_data: Json<ChangeEmailRequestBody>) -> status::Custom<Json<ResponseBody>> {
+    //? Get the required data
+    let new_email = &body_data.new_email;
+    let password = &body_data.password;
+
+    //? Check if the email is valid or not.
+    if !utils::is_valid_email(new_email.as_str()) {
+        return status::Custom(http::Status::BadRequest, Json(ResponseBody { message: format!("Email is not valid!"), success: false, data: None }));
+    }
+
+    //? Generate and store token
+    let (_, confirmation_token) = match db.create_email_change(&user.user, password, new_email).await {
+        Ok(res) => res,
+        Err(err) => {
+            return match err {
+                ErrorType::Unauthorized(_) => status::Custom(http::Status::Unauthorized, Json(ResponseBody { message: format!("Wrong password."), success: false, data: None })),
+                ErrorType::DuplicatesFound(_) => status::Custom(http::Status::Conflict, Json(ResponseBody { message: format!("Email is already used."), success: false, data: None })),
+                _ => status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an unexpected error."), success: false, data: None }))
+            };
+        }
+    };
+
+    //? Send the token to the new email, proving the user owns it
+    match sends_email(new_email, "Email Change Confirmation", format!("Hi there, Please use token below to confirm this address as your new ROVI Project email:<br /><b>TOKEN:[{}]</b>", confirmation_token).as_str()) {
+        Ok(_) => (),
+        Err(_) => return status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an error when trying to send email"), success: false, data: None }))
+    };
+
+    status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully sent email confirmation to {}!", new_email.as_str()), success: true, data: None }))
+}
+
+
+#[post("/user/change_email/confirm", data = "<body_data>")]
+pub async fn change_email_confirm(_api_key: ApiKey, user: &AuthenticatedUser, db: &State<Database>, body_data: Json<ChangeEmailConfirmBody>) -> status::Custom<Json<ResponseBody>> {
+    //? Get the required data
+    let confirmation_token = &body_data.token;
+
+    let user_data: User = match db.confirm_email_change(&user.user, confirmation_token).await {
+        Ok(res) => res,
+        Err(err) => {
+            return match err {
+                ErrorType::Unauthorized(_) => status::Custom(http::Status::Unauthorized, Json(ResponseBody { message: format!("Wrong token."), success: false, data: None })),
+                ErrorType::TokenExpired(_) => status::Custom(http::Status::Unauthorized, Json(ResponseBody { message: format!("Token expired, please request a new one."), success: false, data: None })),
+                ErrorType::DuplicatesFound(_) => status::Custom(http::Status::Conflict, Json(ResponseBody { message: format!("Email is already used."), success: false, data: None })),
+                _ => status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an unexpected error."), success: false, data: None }))
+            };
+        }
+    };
+
+    status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully change email!"), success: true, data: Some(ResponseBodyType::UserUpdate { user_data: user_data.into() }) }))
+}
+
+
+#[post("/user/rotate_mqtt_credentials")]
+pub async fn rotate_mqtt_credentials(_api_key: ApiKey, user: &AuthenticatedUser, db: &State<Database>) -> status::Custom<Json<ResponseBody>> {
+    match db.rotate_mqtt_credentials(&user.user).await {
+        Ok(user_data) => status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully rotate MQTT credentials!"), success: true, data: Some(ResponseBodyType::RotateMqttCredentials { mqtt_user: user_data.mqtt_user, mqtt_pass: user_data.mqtt_pass, previous_credentials_expires_at: user_data.previous_mqtt_credentials.map(|previous| previous.expires_at) }) })),
+        Err(err) => {
+            match err {
+                ErrorType::UserNotFound(_) => status::Custom(http::Status::NotFound, Json(ResponseBody { message: format!("User not found."), success: false, data: None })),
+                _ => status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an unexpected error."), success: false, data: None }))
+            }
+        }
+    }
+}
+
+
+#[post("/user/delete", data = "<body_data>")]
+pub async fn delete_account(_api_key: ApiKey, user: &AuthenticatedUser, db: &State<Database>, body_data: Json<DeleteAccountBody>, cookies: &CookieJar<'_>) -> status::Custom<Json<ResponseBody>> {
+    //? Get the required data
+    let password = &body_data.password;
+    let grace_days: u64 = utils::env_or("ACCOUNT_DELETION_GRACE_DAYS", 14);
+
+    match db.schedule_user_deletion(&user.user, password).await {
+        Ok(_) => {
+            remove_session_cookies(cookies);
+            status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Your account will be deleted in {} days. Login and cancel the deletion to keep it.", grace_days), success: true, data: None }))
+        },
+        Err(err) => {
+            match err {
+                ErrorType::Unauthorized(_) => status::Custom(http::Status::Unauthorized, Json(ResponseBody { message: format!("Wrong password."), success: false, data: None })),
+                _ => status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an unexpected error."), success: false, data: None }))
+            }
+        }
+    }
+}
+
+
+#[post("/user/delete/cancel")]
+pub async fn cancel_delete_account(_api_key: ApiKey, user: &AuthenticatedUser, db: &State<Database>) -> status::Custom<Json<ResponseBody>> {
+    if user.user.deleted_at.is_none() {
+        return status::Custom(http::Status::BadRequest, Json(ResponseBody { message: format!("Account is not scheduled for deletion."), success: false, data: None }));
+    }
+
+    match db.cancel_user_deletion(&user.user.id).await {
+        Ok(_) => status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully cancel account deletion!"), success: true, data: None })),
+        Err(_) => status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an unexpected error."), success: false, data: None }))
+    }
+}
+
+
+#[post("/user/create_device", data = "<body_data>")]
+pub async fn create_device(body_data: Json<CreateDeviceBody>, _api_key: ApiKey, user: &AuthenticatedUser, db: &State<Database>) -> status::Custom<Json<ResponseBody>> {
     let device_name = &body_data.device_name;
 
-    match db.create_device(device_name, &user_email).await {
-        Ok(res) => status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully create device!"), success: true, data: Some(ResponseBodyType::CreateDevice { device_data: res }) })),
+    match db.create_device(device_name, &user.user.id).await {
+        Ok((device_data, device_pass)) => status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully create device!"), success: true, data: Some(ResponseBodyType::CreateDevice { device_data: device_data.into(), device_pass }) })),
         Err(_) => status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an unexpected error."), success: false, data: None }))
     }
 }
 
+#[get("/user/devices?<page>&<per_page>&<status>&<name>")]
+pub async fn list_devices(_api_key: ApiKey, user: &AuthenticatedUser, db: &State<Database>, page: Option<u64>, per_page: Option<u64>, status: Option<&str>, name: Option<&str>) -> status::Custom<Json<ResponseBody>> {
+    let page: u64 = page.unwrap_or(1).max(1);
+    let per_page: u64 = per_page.unwrap_or(20).clamp(1, 100);
+    let status: Option<DeviceStatus> = match status {
+        Some(status) => match status.parse::<DeviceStatus>() {
+            Ok(res) => Some(res),
+            Err(_) => return status::Custom(http::Status::BadRequest, Json(ResponseBody { message: format!("Status must be online or offline."), success: false, data: None }))
+        },
+        None => None
+    };
+
+    match db.list_devices(&user.user.id, status, name, page, per_page).await {
+        Ok((devices, total)) => status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully get devices"), success: true, data: Some(ResponseBodyType::ListDevices { devices: devices.into_iter().map(DeviceData::from).collect(), page, per_page, total }) })),
+        Err(_) => status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an unexpected error."), success: false, data: None }))
+    }
+}
+
+#[get("/user/devices/<id>")]
+pub async fn get_device(_api_key: ApiKey, user: &AuthenticatedUser, db: &State<Database>, id: &str) -> status::Custom<Json<ResponseBody>> {
+    let device_id: ObjectId = match ObjectId::parse_str(id) {
+        Ok(res) => res,
+        Err(_) => return status::Custom(http::Status::BadRequest, Json(ResponseBody { message: format!("Device id is not valid."), success: false, data: None }))
+    };
+
+    match db.get_owned_device(&device_id, &user.user.id).await {
+        Ok(device_data) => status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully get device"), success: true, data: Some(ResponseBodyType::GetDevice { device_data: device_data.into() }) })),
+        Err(err) => device_error_response(err)
+    }
+}
+
+#[patch("/user/devices/<id>", data = "<body_data>")]
+pub async fn update_device(_api_key: ApiKey, user: &AuthenticatedUser, db: &State<Database>, id: &str, body_data: Json<UpdateDeviceBody>) -> status::Custom<Json<ResponseBody>> {
+    let device_id: ObjectId = match ObjectId::parse_str(id) {
+        Ok(res) => res,
+        Err(_) => return status::Custom(http::Status::BadRequest, Json(ResponseBody { message: format!("Device id is not valid."), success: false, data: None }))
+    };
+
+    match db.rename_device(&device_id, &user.user.id, &body_data.device_name).await {
+        Ok(device_data) => status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully update device!"), success: true, data: Some(ResponseBodyType::UpdateDevice { device_data: device_data.into() }) })),
+        Err(err) => device_error_response(err)
+    }
+}
+
+#[delete("/user/devices/<id>")]
+pub async fn delete_device(_api_key: ApiKey, user: &AuthenticatedUser, db: &State<Database>, mqtt: &State<MqttClient>, id: &str) -> status::Custom<Json<ResponseBody>> {
+    let device_id: ObjectId = match ObjectId::parse_str(id) {
+        Ok(res) => res,
+        Err(_) => return status::Custom(http::Status::BadRequest, Json(ResponseBody { message: format!("Device id is not valid."), success: false, data: None }))
+    };
+
+    match db.delete_device(&device_id, &user.user.id).await {
+        Ok(topic_names) => {
+            topic_names.iter().for_each(|topic_name| mqtt.unsubscribe(topic_name));
+            status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully delete device!"), success: true, data: None }))
+        },
+        Err(err) => device_error_response(err)
+    }
+}
+
+#[post("/user/devices/<id>/rotate_credentials", data = "<body_data>")]
+pub async fn rotate_device_credentials(_api_key: ApiKey, user: &AuthenticatedUser, db: &State<Database>, id: &str, body_data: Json<RotateDeviceCredentialsBody>) -> status::Custom<Json<ResponseBody>> {
+    let device_id: ObjectId = match ObjectId::parse_str(id) {
+        Ok(res) => res,
+        Err(_) => return status::Custom(http::Status::BadRequest, Json(ResponseBody { message: format!("Device id is not valid."), success: false, data: None }))
+    };
+
+    let grace_period_seconds: u64 = body_data.grace_period_seconds.unwrap_or(0);
+
+    match db.rotate_device_credentials(&device_id, &user.user.id, grace_period_seconds).await {
+        Ok((device_data, device_pass)) => status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully rotate device credentials!"), success: true, data: Some(ResponseBodyType::RotateDeviceCredentials { device_key: device_data.device_key, device_pass, previous_credentials_expires_at: device_data.previous_credentials.map(|previous| previous.expires_at) }) })),
+        Err(err) => device_error_response(err)
+    }
+}
+
+//? Ranges are in unix milliseconds and default to the last 7 days
+fn connectivity_range(from: Option<i64>, to: Option<i64>) -> Option<(DateTime, DateTime)> {
+    let to_millis: i64 = to.unwrap_or_else(|| DateTime::now().timestamp_millis());
+    let from_millis: i64 = from.unwrap_or(to_millis - 7 * 86_400_000);
+
+    match from_millis < to_millis {
+        true => Some((DateTime::from_millis(from_millis), DateTime::from_millis(to_millis))),
+        false => None
+    }
+}
+
+#[get("/user/devices/<id>/connectivity?<from>&<to>&<page>&<per_page>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn device_connectivity(_api_key: ApiKey, user: &AuthenticatedUser, db: &State<Database>, id: &str, from: Option<i64>, to: Option<i64>, page: Option<u64>, per_page: Option<u64>) -> status::Custom<Json<ResponseBody>> {
+    let device_id: ObjectId = match ObjectId::parse_str(id) {
+        Ok(res) => res,
+        Err(_) => return status::Custom(http::Status::BadRequest, Json(ResponseBody { message: format!("Device id is not valid."), success: false, data: None }))
+    };
+    let Some((from, to)) = connectivity_range(from, to) else {
+        return status::Custom(http::Status::BadRequest, Json(ResponseBody { message: format!("Range start must be before its end."), success: false, data: None }));
+    };
+    let page: u64 = page.unwrap_or(1).max(1);
+    let per_page: u64 = per_page.unwrap_or(50).clamp(1, 500);
+
+    if let Err(err) = db.get_owned_device(&device_id, &user.user.id).await {
+        return device_error_response(err);
+    }
+
+    match db.list_status_changes(&device_id, from, to, page, per_page).await {
+        Ok((events, total)) => status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully get connectivity events"), success: true, data: Some(ResponseBodyType::DeviceConnectivity { events, page, per_page, total }) })),
+        Err(err) => device_error_response(err)
+    }
+}
+
+#[get("/user/devices/<id>/uptime?<from>&<to>")]
+pub async fn device_uptime(_api_key: ApiKey, user: &AuthenticatedUser, db: &State<Database>, id: &str, from: Option<i64>, to: Option<i64>) -> status::Custom<Json<ResponseBody>> {
+    let device_id: ObjectId = match ObjectId::parse_str(id) {
+        Ok(res) => res,
+        Err(_) => return status::Custom(http::Status::BadRequest, Json(ResponseBody { message: format!("Device id is not valid."), success: false, data: None }))
+    };
+    let Some((from, to)) = connectivity_range(from, to) else {
+        return status::Custom(http::Status::BadRequest, Json(ResponseBody { message: format!("Range start must be before its end."), success: false, data: None }));
+    };
+
+    let device_data = match db.get_owned_device(&device_id, &user.user.id).await {
+        Ok(res) => res,
+        Err(err) => return device_error_response(err)
+    };
+
+    match db.get_uptime_report(&device_data, from, to).await {
+        Ok(uptime_report) => status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully get uptime report"), success: true, data: Some(ResponseBodyType::DeviceUptime { uptime_report }) })),
+        Err(err) => device_error_response(err)
+    }
+}
+
+#[put("/user/devices/<id>/retention", data = "<body_data>")]
+pub async fn update_device_retention(_api_key: ApiKey, user: &AuthenticatedUser, db: &State<Database>, id: &str, body_data: Json<UpdateRetentionBody>) -> status::Custom<Json<ResponseBody>> {
+    let device_id: ObjectId = match ObjectId::parse_str(id) {
+        Ok(res) => res,
+        Err(_) => return status::Custom(http::Status::BadRequest, Json(ResponseBody { message: format!("Device id is not valid."), success: false, data: None }))
+    };
+
+    match db.update_device_retention(&device_id, &user.user.id, body_data.retention).await {
+        Ok(device_data) => status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully update device retention!"), success: true, data: Some(ResponseBodyType::UpdateDevice { device_data: device_data.into() }) })),
+        Err(err) => device_error_response(err)
+    }
+}
+
+fn device_error_response(err: ErrorType) -> status::Custom<Json<ResponseBody>> {
+    match err {
+        ErrorType::InvalidConfig(message) => status::Custom(http::Status::BadRequest, Json(ResponseBody { message: message.unwrap_or(format!("Retention is not valid.")), success: false, data: None })),
+        ErrorType::DeviceNotFound(_) => status::Custom(http::Status::NotFound, Json(ResponseBody { message: format!("Device not found."), success: false, data: None })),
+        ErrorType::Unauthorized(_) => status::Custom(http::Status::Forbidden, Json(ResponseBody { message: format!("You don't own this device."), success: false, data: None })),
+        _ => status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an unexpected error."), success: false, data: None }))
+    }
+}
+
 #[post("/user/create_controllable", data = "<body_data>")]
-pub async fn create_controllable(body_data: Json<CreateControllableBody>, _api_key: ApiKey, db: &State<Database>, cookies: &CookieJar<'_>) -> status::Custom<Json<ResponseBody>> {
-    let user_email = match verify_user_token_from_cookie(cookies) {
-        Ok(email) => email,
+pub async fn create_controllable(body_data: Json<CreateControllableBody>, _api_key: ApiKey, user: &AuthenticatedUser, db: &State<Database>, mqtt: &State<MqttClient>) -> status::Custom<Json<ResponseBody>> {
+    let controllable_name = &body_data.controllable_name;
+    let controllable_category = body_data.controllable_category.parse::<ControllableCategory>();
+
+    let controllable_category = match controllable_category {
+        Ok(res) => res,
         Err(_) => {
-            return status::Custom(http::Status::Unauthorized, Json(ResponseBody { message: format!("Unauthorized."), success: false, data: None }));
+            return status::Custom(http::Status::BadRequest, Json(ResponseBody { message: format!("Bad Request Body"), success: false, data: None }))
         }
     };
+
+    let device_id: ObjectId = match ObjectId::parse_str(&body_data.device_id) {
+        Ok(res) => res,
+        Err(_) => return status::Custom(http::Status::BadRequest, Json(ResponseBody { message: format!("Device id is not valid."), success: false, data: None }))
+    };
     
-    let device_id = &body_data.device_id;
-    let controllable_name = &body_data.controllable_name;
-    let controllable_category = ControllableCategory::from_str(&body_data.controllable_category);
 
-    let controllable_category = match controllable_category {
+    match db.create_controllable(&device_id, controllable_name, controllable_category, body_data.controllable_config.clone(), &user.user.id).await {
+        Ok(res) => {
+            mqtt.subscribe(&res.topic_name);
+            status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully create controllable!"), success: true, data: Some(ResponseBodyType::CreateControllable { controllable_data: res }) }))
+        },
+        Err(err) => controllable_error_response(err)
+    }
+}
+
+#[get("/user/devices/<id>/controllables")]
+pub async fn list_controllables(_api_key: ApiKey, user: &AuthenticatedUser, db: &State<Database>, id: &str) -> status::Custom<Json<ResponseBody>> {
+    let device_id: ObjectId = match ObjectId::parse_str(id) {
+        Ok(res) => res,
+        Err(_) => return status::Custom(http::Status::BadRequest, Json(ResponseBody { message: format!("Device id is not valid."), success: false, data: None }))
+    };
+
+    match db.list_controllables(&device_id, &user.user.id).await {
+        Ok(controllables) => status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully get controllables"), success: true, data: Some(ResponseBodyType::ListControllables { controllables }) })),
+        Err(err) => controllable_error_response(err)
+    }
+}
+
+#[get("/user/controllables/<id>")]
+pub async fn get_user_controllable(_api_key: ApiKey, user: &AuthenticatedUser, db: &State<Database>, id: &str) -> status::Custom<Json<ResponseBody>> {
+    let controllable_id: ObjectId = match ObjectId::parse_str(id) {
+        Ok(res) => res,
+        Err(_) => return status::Custom(http::Status::BadRequest, Json(ResponseBody { message: format!("Controllable id is not valid."), success: false, data: None }))
+    };
+
+    match db.get_owned_controllable(&controllable_id, &user.user.id).await {
+        Ok(controllable_data) => status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully get controllable"), success: true, data: Some(ResponseBodyType::GetControllable { controllable_data }) })),
+        Err(err) => controllable_error_response(err)
+    }
+}
+
+#[patch("/user/controllables/<id>", data = "<body_data>")]
+pub async fn update_controllable(_api_key: ApiKey, user: &AuthenticatedUser, db: &State<Database>, id: &str, body_data: Json<UpdateControllableBody>) -> status::Custom<Json<ResponseBody>> {
+    let controllable_id: ObjectId = match ObjectId::parse_str(id) {
+        Ok(res) => res,
+        Err(_) => return status::Custom(http::Status::BadRequest, Json(ResponseBody { message: format!("Controllable id is not valid."), success: false, data: None }))
+    };
+
+    let controllable_category: Option<ControllableCategory> = match &body_data.controllable_category {
+        Some(category) => match category.parse::<ControllableCategory>() {
+            Ok(res) => Some(res),
+            Err(_) => return status::Custom(http::Status::BadRequest, Json(ResponseBody { message: format!("Bad Request Body"), success: false, data: None }))
+        },
+        None => None
+    };
+
+    match db.update_controllable(&controllable_id, &user.user.id, body_data.controllable_name.as_deref(), controllable_category, body_data.controllable_config.clone()).await {
+        Ok(controllable_data) => status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully update controllable!"), success: true, data: Some(ResponseBodyType::UpdateControllable { controllable_data }) })),
+        Err(err) => controllable_error_response(err)
+    }
+}
+
+#[delete("/user/controllables/<id>")]
+pub async fn delete_controllable(_api_key: ApiKey, user: &AuthenticatedUser, db: &State<Database>, mqtt: &State<MqttClient>, id: &str) -> status::Custom<Json<ResponseBody>> {
+    let controllable_id: ObjectId = match ObjectId::parse_str(id) {
+        Ok(res) => res,
+        Err(_) => return status::Custom(http::Status::BadRequest, Json(ResponseBody { message: format!("Controllable id is not valid."), success: false, data: None }))
+    };
+
+    match db.delete_controllable(&controllable_id, &user.user.id).await {
+        Ok(controllable_data) => {
+            mqtt.unsubscribe(&controllable_data.topic_name);
+            status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully delete controllable!"), success: true, data: None }))
+        },
+        Err(err) => controllable_error_response(err)
+    }
+}
+
+#[post("/user/controllables/<id>/command", data = "<body_data>")]
+pub async fn send_command(_api_key: ApiKey, user: &AuthenticatedUser, db: &State<Database>, mqtt: &State<MqttClient>, id: &str, body_data: Json<SendCommandBody>) -> status::Custom<Json<ResponseBody>> {
+    let controllable_id: ObjectId = match ObjectId::parse_str(id) {
+        Ok(res) => res,
+        Err(_) => return status::Custom(http::Status::BadRequest, Json(ResponseBody { message: format!("Controllable id is not valid."), success: false, data: None }))
+    };
+
+    let controllable_data = match db.get_owned_controllable(&controllable_id, &user.user.id).await {
+        Ok(res) => res,
+        Err(err) => return controllable_error_response(err)
+    };
+
+    if controllable_data.direction == ControllableDirection::Input {
+        return status::Custom(http::Status::BadRequest, Json(ResponseBody { message: format!("Sensors can't take commands."), success: false, data: None }));
+    }
+
+    // Validate the value against the controllable's config
+    let payload: String = match controllable_data.config().command_payload(&body_data.value) {
         Some(res) => res,
+        None => return status::Custom(http::Status::BadRequest, Json(ResponseBody { message: format!("Value is not valid for this controllable."), success: false, data: None }))
+    };
+
+    let qos_level: u8 = body_data.qos.unwrap_or_else(|| utils::env_or("MQTT_COMMAND_QOS", 1));
+    let qos = match rumqttc::qos(qos_level) {
+        Ok(res) => res,
+        Err(_) => return status::Custom(http::Status::BadRequest, Json(ResponseBody { message: format!("QoS must be 0, 1 or 2."), success: false, data: None }))
+    };
+    let retain: bool = body_data.retain.unwrap_or_else(|| utils::env_or("MQTT_COMMAND_RETAIN", false));
+
+    if let Err(err) = mqtt.publish(&controllable_data.command_topic(), &payload, qos, retain).await {
+        return controllable_error_response(err);
+    }
+
+    // Keep track of who sent what
+    match db.insert_command(CommandTable::new(&controllable_data, user.user.id, payload, qos_level as i32, retain)).await {
+        Ok(command_data) => status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully send command!"), success: true, data: Some(ResponseBodyType::SendCommand { command_data }) })),
+        Err(err) => controllable_error_response(err)
+    }
+}
+
+//? Without `bucket` the raw readings are paginated, with it every bucket in the range is returned at once
+#[get("/user/controllables/<id>/history?<from>&<to>&<bucket>&<page>&<per_page>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn controllable_history(_api_key: ApiKey, user: &AuthenticatedUser, db: &State<Database>, id: &str, from: Option<i64>, to: Option<i64>, bucket: Option<&str>, page: Option<u64>, per_page: Option<u64>) -> status::Custom<Json<ResponseBody>> {
+    let controllable_id: ObjectId = match ObjectId::parse_str(id) {
+        Ok(res) => res,
+        Err(_) => return status::Custom(http::Status::BadRequest, Json(ResponseBody { message: format!("Controllable id is not valid."), success: false, data: None }))
+    };
+
+    // The range is in unix milliseconds and defaults to the last 24 hours
+    let to_millis: i64 = to.unwrap_or_else(|| DateTime::now().timestamp_millis());
+    let from_millis: i64 = from.unwrap_or(to_millis - 86_400_000);
+    if from_millis >= to_millis {
+        return status::Custom(http::Status::BadRequest, Json(ResponseBody { message: format!("Range start must be before its end."), success: false, data: None }));
+    }
+    let (from, to) = (DateTime::from_millis(from_millis), DateTime::from_millis(to_millis));
+
+    let controllable_data = match db.get_owned_controllable(&controllable_id, &user.user.id).await {
+        Ok(res) => res,
+        Err(err) => return controllable_error_response(err)
+    };
+
+    let bucket_size: HistoryBucketSize = match bucket {
+        Some(bucket) => match bucket.parse::<HistoryBucketSize>() {
+            Ok(res) => res,
+            Err(_) => return status::Custom(http::Status::BadRequest, Json(ResponseBody { message: format!("Bucket must be 1m, 1h or 1d."), success: false, data: None }))
+        },
         None => {
-            return status::Custom(http::Status::BadRequest, Json(ResponseBody { message: format!("Bad Request Body"), success: false, data: None }))
+            let page: u64 = page.unwrap_or(1).max(1);
+            let per_page: u64 = per_page.unwrap_or(100).clamp(1, 1000);
+
+            return match db.list_controllable_readings(&controllable_id, from, to, page, per_page).await {
+                Ok((readings, total)) => status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully get readings"), success: true, data: Some(ResponseBodyType::ControllableReadings { from, to, readings, page, per_page, total }) })),
+                Err(err) => controllable_error_response(err)
+            };
         }
     };
-    
 
-    match db.create_controllable(device_id, &controllable_name, controllable_category, &user_email).await {
-        Ok(res) => status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully create device!"), success: true, data: Some(ResponseBodyType::CreateControllable { controllable_data: res }) })),
-        Err(_) => status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an unexpected error."), success: false, data: None }))
+    //? Keep a single response bounded, wide ranges need a coarser bucket
+    let max_buckets: i64 = utils::env_or("HISTORY_MAX_BUCKETS", 5000);
+    if (to_millis - from_millis) / bucket_size.millis() > max_buckets {
+        return status::Custom(http::Status::BadRequest, Json(ResponseBody { message: format!("Range is too wide for this bucket size."), success: false, data: None }));
     }
-}
\ No newline at end of file
+
+    match db.get_controllable_history(&controllable_data, &user.user, from, to, bucket_size).await {
+        Ok(buckets) => status::Custom(http::Status::Ok, Json(ResponseBody { message: format!("Successfully get history"), success: true, data: Some(ResponseBodyType::ControllableHistory { bucket: bucket.unwrap_or_default().to_string(), from, to, buckets }) })),
+        Err(err) => controllable_error_response(err)
+    }
+}
+
+fn controllable_error_response(err: ErrorType) -> status::Custom<Json<ResponseBody>> {
+    match err {
+        ErrorType::DeviceNotFound(_) => status::Custom(http::Status::NotFound, Json(ResponseBody { message: format!("Device not found."), success: false, data: None })),
+        ErrorType::ControllableNotFound(_) => status::Custom(http::Status::NotFound, Json(ResponseBody { message: format!("Controllable not found."), success: false, data: None })),
+        ErrorType::Unauthorized(_) => status::Custom(http::Status::Forbidden, Json(ResponseBody { message: format!("You don't own this device."), success: false, data: None })),
+        ErrorType::DuplicatesFound(_) => status::Custom(http::Status::Conflict, Json(ResponseBody { message: format!("Controllable name is already used."), success: false, data: None })),
+        ErrorType::InvalidConfig(message) => status::Custom(http::Status::BadRequest, Json(ResponseBody { message: message.unwrap_or(format!("Controllable config is not valid.")), success: false, data: None })),
+        ErrorType::BrokerUnavailable(_) => status::Custom(http::Status::ServiceUnavailable, Json(ResponseBody { message: format!("MQTT broker is unavailable."), success: false, data: None })),
+        _ => status::Custom(http::Status::InternalServerError, Json(ResponseBody { message: format!("There's an unexpected error."), success: false, data: None }))
+    }
+}
@@ -0,0 +1,210 @@
+use std::time::Duration;
+
+use futures::TryStreamExt;
+use mongodb::{bson::{doc, oid::ObjectId, DateTime}, options::ReturnDocument};
+
+use crate::types::{db_model::{Device, DeviceStatus, DeviceStatusChangeTable, UptimeReport}, error::ErrorType};
+
+use super::Database;
+
+impl Database {
+    //? Any sign of life from a device goes through here, a flip from offline is recorded as a status change
+    pub async fn mark_device_online(&self, device_id: &ObjectId, reason: &str) -> Result<Device, ErrorType> {
+        let now: DateTime = DateTime::now();
+
+        let mut device_data: Device = match self.device.find_one_and_update(doc! {
+            "_id": device_id
+        }, doc! {
+            "$set": {
+                "last_online": now,
+                "status": i32::from(DeviceStatus::Online)
+            }
+        }).return_document(ReturnDocument::Before).await {
+            Ok(Some(res)) => res,
+            Ok(None) => return Err(ErrorType::DeviceNotFound(None)),
+            Err(err) => {
+                println!("There's an error when trying to update device status. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        //? A device initializing while still online rebooted faster than the silence window, the log keeps that too
+        if device_data.status != DeviceStatus::Online || reason == "initialization" {
+            self.record_status_change(DeviceStatusChangeTable::new(&device_data, device_data.status, DeviceStatus::Online, reason, now)).await;
+        }
+
+        device_data.status = DeviceStatus::Online;
+        device_data.last_online = Some(now);
+
+        Ok(device_data)
+    }
+
+    //? Devices silent for longer than `silence_window` are flipped offline, returns how many were
+    pub async fn mark_stale_devices_offline(&self, silence_window: Duration) -> Result<u64, ErrorType> {
+        let cutoff: DateTime = DateTime::from_millis(DateTime::now().timestamp_millis() - silence_window.as_millis() as i64);
+        let stale_filter = doc! {
+            "status": i32::from(DeviceStatus::Online),
+            "$or": [
+                { "last_online": { "$lt": cutoff } },
+                { "last_online": null }
+            ]
+        };
+
+        let stale_devices: Vec<Device> = match self.device.find(stale_filter.clone()).await {
+            Ok(cursor) => match cursor.try_collect().await {
+                Ok(res) => res,
+                Err(err) => {
+                    println!("There's an error when trying to read stale devices. Error: {}", err);
+                    return Err(ErrorType::UnknownError(Some(err.to_string())));
+                }
+            },
+            Err(err) => {
+                println!("There's an error when trying to get stale devices. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        let mut offline_count: u64 = 0;
+        for device_data in stale_devices {
+            //? The filter is repeated so a heartbeat landing in between keeps the device online
+            let mut device_filter = stale_filter.clone();
+            device_filter.insert("_id", device_data.id);
+
+            match self.device.update_one(device_filter, doc! {
+                "$set": { "status": i32::from(DeviceStatus::Offline) }
+            }).await {
+                Ok(res) if res.modified_count == 1 => {
+                    let changed_at: DateTime = device_data.last_online.unwrap_or(cutoff);
+                    self.record_status_change(DeviceStatusChangeTable::new(&device_data, DeviceStatus::Online, DeviceStatus::Offline, "timeout", changed_at)).await;
+                    offline_count += 1;
+                },
+                Ok(_) => (),
+                Err(err) => println!("There's an error when trying to mark device {} offline. Error: {}", device_data.id, err)
+            };
+        }
+
+        Ok(offline_count)
+    }
+
+    pub async fn list_status_changes(&self, device_id: &ObjectId, from: DateTime, to: DateTime, page: u64, per_page: u64) -> Result<(Vec<DeviceStatusChangeTable>, u64), ErrorType> {
+        let filter = doc! { "device_id": device_id, "changed_at": { "$gte": from, "$lt": to } };
+
+        let total: u64 = match self.device_status_change.count_documents(filter.clone()).await {
+            Ok(res) => res,
+            Err(err) => {
+                println!("There's an error when trying to count device status changes. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        let status_changes: Vec<DeviceStatusChangeTable> = match self.device_status_change.find(filter)
+            .sort(doc! { "changed_at": -1, "_id": -1 })
+            .skip(page.saturating_sub(1) * per_page)
+            .limit(per_page as i64)
+            .await {
+            Ok(cursor) => match cursor.try_collect().await {
+                Ok(res) => res,
+                Err(err) => {
+                    println!("There's an error when trying to read device status changes. Error: {}", err);
+                    return Err(ErrorType::UnknownError(Some(err.to_string())));
+                }
+            },
+            Err(err) => {
+                println!("There's an error when trying to get device status changes. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        Ok((status_changes, total))
+    }
+
+    //? Replays the status changes over the range, starting from the status the device had when it began
+    pub async fn get_uptime_report(&self, device_data: &Device, from: DateTime, to: DateTime) -> Result<UptimeReport, ErrorType> {
+        //? Nothing can be known about the time before the device existed or after now
+        let start_millis: i64 = from.timestamp_millis().max(device_data.created_at.timestamp_millis());
+        let end_millis: i64 = to.timestamp_millis().min(DateTime::now().timestamp_millis());
+        if start_millis >= end_millis {
+            return Ok(UptimeReport::empty(from, to));
+        }
+        let (start, end) = (DateTime::from_millis(start_millis), DateTime::from_millis(end_millis));
+
+        //? A device that was never online before the range can't reconnect until it has dropped off once
+        let (initial_status, initially_dropped) = match self.device_status_change.find_one(doc! {
+            "device_id": device_data.id,
+            "changed_at": { "$lt": start }
+        }).sort(doc! { "changed_at": -1, "_id": -1 }).await {
+            Ok(Some(res)) => (res.to_status, res.from_status == DeviceStatus::Online && res.to_status == DeviceStatus::Offline),
+            Ok(None) => (DeviceStatus::Offline, false),
+            Err(err) => {
+                println!("There's an error when trying to get device status change. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        let status_changes: Vec<DeviceStatusChangeTable> = match self.device_status_change.find(doc! {
+            "device_id": device_data.id,
+            "changed_at": { "$gte": start, "$lt": end }
+        }).sort(doc! { "changed_at": 1, "_id": 1 }).await {
+            Ok(cursor) => match cursor.try_collect().await {
+                Ok(res) => res,
+                Err(err) => {
+                    println!("There's an error when trying to read device status changes. Error: {}", err);
+                    return Err(ErrorType::UnknownError(Some(err.to_string())));
+                }
+            },
+            Err(err) => {
+                println!("There's an error when trying to get device status changes. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        let mut online_millis: i64 = 0;
+        let mut longest_outage_millis: i64 = 0;
+        let mut reconnects: u64 = 0;
+        let mut current_status: DeviceStatus = initial_status;
+        let mut dropped: bool = initially_dropped;
+        let mut period_start: i64 = start_millis;
+
+        //? Each change closes the period the device spent in its previous status
+        for status_change in &status_changes {
+            let changed_at: i64 = status_change.changed_at.timestamp_millis();
+            match current_status {
+                DeviceStatus::Online => online_millis += changed_at - period_start,
+                DeviceStatus::Offline => longest_outage_millis = longest_outage_millis.max(changed_at - period_start)
+            };
+
+            //? Only coming back after a real drop is a reconnect, the first connection and reboots aren't
+            match (status_change.from_status, status_change.to_status) {
+                (DeviceStatus::Online, DeviceStatus::Offline) => dropped = true,
+                (DeviceStatus::Offline, DeviceStatus::Online) if dropped => {
+                    reconnects += 1;
+                    dropped = false;
+                },
+                _ => ()
+            };
+
+            current_status = status_change.to_status;
+            period_start = changed_at;
+        }
+
+        match current_status {
+            DeviceStatus::Online => online_millis += end_millis - period_start,
+            DeviceStatus::Offline => longest_outage_millis = longest_outage_millis.max(end_millis - period_start)
+        };
+
+        Ok(UptimeReport {
+            from,
+            to,
+            reconnects,
+            uptime_percent: online_millis as f64 / (end_millis - start_millis) as f64 * 100.0,
+            online_seconds: online_millis / 1000,
+            longest_outage_seconds: longest_outage_millis / 1000
+        })
+    }
+
+    async fn record_status_change(&self, status_change: DeviceStatusChangeTable) {
+        if let Err(err) = self.device_status_change.insert_one(&status_change).await {
+            println!("There's an error when trying to record device status change. Error: {}", err);
+        }
+    }
+}
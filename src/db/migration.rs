@@ -0,0 +1,61 @@
+use futures::TryStreamExt;
+use mongodb::{bson::{doc, Document}, Collection};
+
+use super::Database;
+
+impl Database {
+    //? Devices and controllables used to reference their owner by `user_email`, rewrite them to `owner_id`.
+    //? Documents already migrated don't match the filter, so running this again is a no-op.
+    pub async fn migrate_owner_ids(&self) {
+        let device_col: Collection<Document> = self.device.clone_with_type::<Document>();
+        let controllable_col: Collection<Document> = self.controllable.clone_with_type::<Document>();
+
+        for (collection_label, collection) in [("device", device_col), ("controllable", controllable_col)] {
+            let legacy_documents: Vec<Document> = match collection.find(doc! {
+                "user_email": { "$exists": true },
+                "owner_id": { "$exists": false }
+            }).await {
+                Ok(cursor) => match cursor.try_collect().await {
+                    Ok(res) => res,
+                    Err(err) => {
+                        println!("[Migration] There's an error when trying to read {} data. Error: {}", collection_label, err);
+                        continue;
+                    }
+                },
+                Err(err) => {
+                    println!("[Migration] There's an error when trying to get {} data. Error: {}", collection_label, err);
+                    continue;
+                }
+            };
+
+            let mut migrated_count: u64 = 0;
+            for legacy_document in legacy_documents.iter() {
+                let (Ok(document_id), Ok(user_email)) = (legacy_document.get_object_id("_id"), legacy_document.get_str("user_email")) else {
+                    continue;
+                };
+
+                let user_data = match self.get_user(user_email).await {
+                    Ok(res) => res,
+                    Err(_) => {
+                        println!("[Migration] Can't find owner '{}' of {} {}, skipping it", user_email, collection_label, document_id);
+                        continue;
+                    }
+                };
+
+                match collection.update_one(doc! {
+                    "_id": document_id
+                }, doc! {
+                    "$set": { "owner_id": user_data.id },
+                    "$unset": { "user_email": "" }
+                }).await {
+                    Ok(_) => migrated_count += 1,
+                    Err(err) => println!("[Migration] There's an error when trying to migrate {} {}. Error: {}", collection_label, document_id, err)
+                };
+            }
+
+            if migrated_count > 0 {
+                println!("[Migration] Moved {} {} document(s) to owner_id", migrated_count, collection_label);
+            }
+        }
+    }
+}
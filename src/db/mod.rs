@@ -1,17 +1,108 @@
-use mongodb::{bson::{doc, oid::ObjectId, DateTime}, options::ClientOptions, Client, Collection};
+pub mod migration;
+pub mod retention;
+pub mod connectivity;
 
-use crate::types::{db_model::{Controllable, ControllableCategory, Device, LoginOTPTable, RegistrationTable, User}, error::ErrorType};
+use std::{collections::{BTreeMap, HashMap}, time::Duration};
 
+use futures::TryStreamExt;
+use mongodb::{bson::{doc, oid::ObjectId, DateTime, Document}, error::{ErrorKind, WriteFailure}, options::{ClientOptions, IndexOptions, ReturnDocument, TimeseriesGranularity, TimeseriesOptions}, Client, Collection, IndexModel};
+use subtle::ConstantTimeEq;
+
+use crate::{types::{db_model::{COMMAND_TOPIC_SUFFIX, DeviceStatus, DeviceStatusChangeTable, RetentionPolicy, TelemetryRollupTable, HistoryBucket, HistoryBucketSize, HistoryReading, TelemetryTable, CommandTable, Controllable, ControllableCategory, ControllableConfig, Device, EmailChangeTable, PreviousDeviceCredentials, PreviousMqttCredentials, LoginOTPAttempt, LoginOTPTable, PasswordResetTable, RegistrationTable, SessionTable, User}, error::ErrorType}, utils::{env_or, generate_long_token, generate_token, hash_password, hash_token, verify_password, PasswordVerification}};
+
+fn is_duplicate_key_error(err: &mongodb::error::Error) -> bool {
+    match *err.kind {
+        ErrorKind::Write(WriteFailure::WriteError(ref write_error)) => write_error.code == 11000,
+        ErrorKind::Command(ref command_error) => command_error.code == 11000,
+        _ => false
+    }
+}
+
+async fn aggregate_history<T: Send + Sync>(collection: &Collection<T>, pipeline: Vec<Document>) -> Result<Vec<HistoryBucket>, ErrorType> {
+    let bucket_documents: Vec<Document> = match collection.aggregate(pipeline).await {
+        Ok(cursor) => match cursor.try_collect().await {
+            Ok(res) => res,
+            Err(err) => {
+                println!("There's an error when trying to read telemetry history. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        },
+        Err(err) => {
+            println!("There's an error when trying to aggregate telemetry history. Error: {}", err);
+            return Err(ErrorType::UnknownError(Some(err.to_string())));
+        }
+    };
+
+    let mut buckets: Vec<HistoryBucket> = Vec::with_capacity(bucket_documents.len());
+    for bucket_document in bucket_documents {
+        match mongodb::bson::from_document(bucket_document) {
+            Ok(res) => buckets.push(res),
+            Err(err) => return Err(ErrorType::UnknownError(Some(err.to_string())))
+        };
+    }
+
+    Ok(buckets)
+}
+
+//? Uses the category default when no config is given, otherwise the config has to fit the category
+fn resolve_controllable_config(controllable_category: ControllableCategory, controllable_config: Option<ControllableConfig>) -> Result<ControllableConfig, ErrorType> {
+    let controllable_config: ControllableConfig = match controllable_config {
+        Some(res) => res,
+        None => return Ok(ControllableConfig::default_for(&controllable_category))
+    };
+
+    if controllable_config.category() != controllable_category {
+        return Err(ErrorType::InvalidConfig(Some(String::from("Config doesn't match the controllable category."))));
+    }
+
+    match controllable_config.validate() {
+        Ok(_) => Ok(controllable_config),
+        Err(message) => Err(ErrorType::InvalidConfig(Some(message)))
+    }
+}
+
+//? Time-series collections have to be created explicitly, a plain insert would make a regular one.
+//? `expire_after` is enforced by Mongo for the whole collection, an existing collection gets it updated.
+async fn create_time_series_collection(db: &mongodb::Database, collection_name: &str, expire_after: Duration) {
+    let timeseries_options: TimeseriesOptions = TimeseriesOptions::builder()
+        .time_field(String::from("timestamp"))
+        .meta_field(Some(String::from("meta")))
+        .granularity(Some(TimeseriesGranularity::Seconds))
+        .build();
+
+    match db.create_collection(collection_name).timeseries(timeseries_options).expire_after_seconds(expire_after).await {
+        Ok(_) => (),
+        Err(err) if matches!(*err.kind, ErrorKind::Command(ref command_error) if command_error.code == 48) => {
+            if let Err(err) = db.run_command(doc! { "collMod": collection_name, "expireAfterSeconds": expire_after.as_secs() as i64 }).await {
+                println!("There's an error when trying to update {} expiry. Error: {}", collection_name, err);
+            }
+        },
+        Err(err) => println!("There's an error when trying to create {} time-series collection. Error: {}", collection_name, err)
+    };
+}
+
+#[derive(Clone)]
 pub struct Database {
+    client: Client,
     user: Collection<User>,
     registration: Collection<RegistrationTable>,
     device: Collection<Device>,
     controllable: Collection<Controllable>,
-    otp: Collection<LoginOTPTable>
+    otp: Collection<LoginOTPTable>,
+    otp_attempt: Collection<LoginOTPAttempt>,
+    session: Collection<SessionTable>,
+    password_reset: Collection<PasswordResetTable>,
+    email_change: Collection<EmailChangeTable>,
+    command: Collection<CommandTable>,
+    telemetry: Collection<TelemetryTable>,
+    telemetry_hourly: Collection<TelemetryRollupTable>,
+    telemetry_daily: Collection<TelemetryRollupTable>,
+    device_status_change: Collection<DeviceStatusChangeTable>
 }
 
 impl Database {
-    pub async fn new(mongodb_uri: &str, database_name: &str, user_collection_name: &str, reg_table_collection_name: &str, device_collection_name: &str, controllable_collection_name: &str, otp_collection_name: &str) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub async fn new(mongodb_uri: &str, database_name: &str, user_collection_name: &str, reg_table_collection_name: &str, device_collection_name: &str, controllable_collection_name: &str, otp_collection_name: &str, otp_attempt_collection_name: &str, session_collection_name: &str, password_reset_collection_name: &str, email_change_collection_name: &str, command_collection_name: &str, telemetry_collection_name: &str, telemetry_hourly_collection_name: &str, telemetry_daily_collection_name: &str, device_status_change_collection_name: &str) -> Self {
         let options: ClientOptions = ClientOptions::parse(mongodb_uri).await.unwrap();
         let client: Client = Client::with_options(options).unwrap();
         let db: mongodb::Database = client.database(database_name);
@@ -20,13 +111,128 @@ impl Database {
         let device_col: Collection<Device> = db.collection::<Device>(device_collection_name);
         let controllable_col: Collection<Controllable> = db.collection::<Controllable>(controllable_collection_name);
         let otp_col: Collection<LoginOTPTable> = db.collection::<LoginOTPTable>(otp_collection_name);
+        let otp_attempt_col: Collection<LoginOTPAttempt> = db.collection::<LoginOTPAttempt>(otp_attempt_collection_name);
+        let session_col: Collection<SessionTable> = db.collection::<SessionTable>(session_collection_name);
+        let password_reset_col: Collection<PasswordResetTable> = db.collection::<PasswordResetTable>(password_reset_collection_name);
+        let email_change_col: Collection<EmailChangeTable> = db.collection::<EmailChangeTable>(email_change_collection_name);
+        let command_col: Collection<CommandTable> = db.collection::<CommandTable>(command_collection_name);
 
-        Self {
+        create_time_series_collection(&db, telemetry_collection_name, Duration::from_secs(RetentionPolicy::max_raw_days() as u64 * 86400)).await;
+        let telemetry_col: Collection<TelemetryTable> = db.collection::<TelemetryTable>(telemetry_collection_name);
+        let telemetry_hourly_col: Collection<TelemetryRollupTable> = db.collection::<TelemetryRollupTable>(telemetry_hourly_collection_name);
+        let telemetry_daily_col: Collection<TelemetryRollupTable> = db.collection::<TelemetryRollupTable>(telemetry_daily_collection_name);
+        let device_status_change_col: Collection<DeviceStatusChangeTable> = db.collection::<DeviceStatusChangeTable>(device_status_change_collection_name);
+
+        let database = Self {
+            client,
             user: user_col,
             registration: registration_col,
             device: device_col,
             controllable: controllable_col,
-            otp: otp_col
+            otp: otp_col,
+            otp_attempt: otp_attempt_col,
+            session: session_col,
+            password_reset: password_reset_col,
+            email_change: email_change_col,
+            command: command_col,
+            telemetry: telemetry_col,
+            telemetry_hourly: telemetry_hourly_col,
+            telemetry_daily: telemetry_daily_col,
+            device_status_change: device_status_change_col
+        };
+
+        database.create_indexes().await;
+
+        database
+    }
+
+    async fn create_indexes(&self) {
+        //? Let mongo purge the rows by itself once `expires_at` has passed
+        let expire_on_date = || IndexOptions::builder().expire_after(Duration::from_secs(0)).build();
+
+        if let Err(err) = self.otp.create_index(IndexModel::builder().keys(doc! { "expires_at": 1 }).options(expire_on_date()).build()).await {
+            println!("There's an error when trying to create OTP TTL index. Error: {}", err);
+        }
+
+        if let Err(err) = self.otp_attempt.create_index(IndexModel::builder().keys(doc! { "expires_at": 1 }).options(expire_on_date()).build()).await {
+            println!("There's an error when trying to create OTP attempt TTL index. Error: {}", err);
+        }
+
+        //? One counter per email, concurrent wrong guesses must not each upsert their own row
+        if let Err(err) = self.otp_attempt.create_index(IndexModel::builder().keys(doc! { "email": 1 }).options(IndexOptions::builder().unique(true).build()).build()).await {
+            println!("There's an error when trying to create OTP attempt email index. Error: {}", err);
+        }
+
+        if let Err(err) = self.session.create_index(IndexModel::builder().keys(doc! { "expires_at": 1 }).options(expire_on_date()).build()).await {
+            println!("There's an error when trying to create session TTL index. Error: {}", err);
+        }
+
+        if let Err(err) = self.session.create_index(IndexModel::builder().keys(doc! { "refresh_token_hash": 1 }).options(IndexOptions::builder().unique(true).build()).build()).await {
+            println!("There's an error when trying to create session token index. Error: {}", err);
+        }
+
+        if let Err(err) = self.password_reset.create_index(IndexModel::builder().keys(doc! { "expires_at": 1 }).options(expire_on_date()).build()).await {
+            println!("There's an error when trying to create password reset TTL index. Error: {}", err);
+        }
+
+        if let Err(err) = self.email_change.create_index(IndexModel::builder().keys(doc! { "expires_at": 1 }).options(expire_on_date()).build()).await {
+            println!("There's an error when trying to create email change TTL index. Error: {}", err);
+        }
+
+        //? Usernames log in and emails receive codes, so both must be unique, these make concurrent writes fail instead of racing
+        if let Err(err) = self.user.create_index(IndexModel::builder().keys(doc! { "email": 1 }).options(IndexOptions::builder().unique(true).build()).build()).await {
+            println!("There's an error when trying to create user email index. Error: {}", err);
+        }
+
+        if let Err(err) = self.user.create_index(IndexModel::builder().keys(doc! { "username": 1 }).options(IndexOptions::builder().unique(true).build()).build()).await {
+            println!("There's an error when trying to create username index. Error: {}", err);
+        }
+
+        if let Err(err) = self.user.create_index(IndexModel::builder().keys(doc! { "mqtt_user": 1 }).build()).await {
+            println!("There's an error when trying to create MQTT user index. Error: {}", err);
+        }
+
+        if let Err(err) = self.user.create_index(IndexModel::builder().keys(doc! { "previous_mqtt_credentials.mqtt_user": 1 }).build()).await {
+            println!("There's an error when trying to create previous MQTT user index. Error: {}", err);
+        }
+
+        if let Err(err) = self.device.create_index(IndexModel::builder().keys(doc! { "device_key": 1 }).options(IndexOptions::builder().unique(true).build()).build()).await {
+            println!("There's an error when trying to create device key index. Error: {}", err);
+        }
+
+        if let Err(err) = self.device.create_index(IndexModel::builder().keys(doc! { "previous_credentials.device_key": 1 }).build()).await {
+            println!("There's an error when trying to create previous device key index. Error: {}", err);
+        }
+
+        for (rollup_label, rollup_col) in [("hourly", &self.telemetry_hourly), ("daily", &self.telemetry_daily)] {
+            if let Err(err) = rollup_col.create_index(IndexModel::builder().keys(doc! { "expires_at": 1 }).options(expire_on_date()).build()).await {
+                println!("There's an error when trying to create {} rollup TTL index. Error: {}", rollup_label, err);
+            }
+
+            //? Rollups are merged on these, rerunning a window replaces its buckets instead of duplicating them
+            if let Err(err) = rollup_col.create_index(IndexModel::builder().keys(doc! { "controllable_id": 1, "bucket_start": 1 }).options(IndexOptions::builder().unique(true).build()).build()).await {
+                println!("There's an error when trying to create {} rollup bucket index. Error: {}", rollup_label, err);
+            }
+        }
+
+        if let Err(err) = self.device.create_index(IndexModel::builder().keys(doc! { "status": 1, "last_online": 1 }).build()).await {
+            println!("There's an error when trying to create device status index. Error: {}", err);
+        }
+
+        if let Err(err) = self.device_status_change.create_index(IndexModel::builder().keys(doc! { "device_id": 1, "changed_at": 1 }).build()).await {
+            println!("There's an error when trying to create device status change index. Error: {}", err);
+        }
+
+        if let Err(err) = self.command.create_index(IndexModel::builder().keys(doc! { "controllable_id": 1, "created_at": -1 }).build()).await {
+            println!("There's an error when trying to create command index. Error: {}", err);
+        }
+
+        if let Err(err) = self.controllable.create_index(IndexModel::builder().keys(doc! { "topic_name": 1 }).build()).await {
+            println!("There's an error when trying to create controllable topic index. Error: {}", err);
+        }
+
+        if let Err(err) = self.controllable.create_index(IndexModel::builder().keys(doc! { "device_id": 1, "controllable_name": 1 }).options(IndexOptions::builder().unique(true).build()).build()).await {
+            println!("There's an error when trying to create controllable name index. Error: {}", err);
         }
     }
 
@@ -51,6 +257,19 @@ impl Database {
         }
     }
 
+    pub async fn get_user_by_id(&self, user_id: &ObjectId) -> Result<User, ErrorType> {
+        match self.user.find_one(doc! {
+            "_id": user_id
+        }).await {
+            Ok(Some(user_data)) => Ok(user_data),
+            Ok(None) => Err(ErrorType::UserNotFound(None)),
+            Err(err) => {
+                println!("There's an error when trying to get user data. Error: {}", err);
+                Err(ErrorType::UnknownError(Some(err.to_string())))
+            }
+        }
+    }
+
     pub async fn insert_registration(&self, email: &str) -> Result<RegistrationTable, ErrorType> {
         println!("[Insert Registration] Email: {}", email);
 
@@ -69,6 +288,14 @@ impl Database {
             return Err(ErrorType::DuplicatesFound(None));
         }
 
+
+        //? Reuse the pending registration of this email if there's any
+        match self.refresh_registration(email).await {
+            Ok(registration_data) => return Ok(registration_data),
+            Err(ErrorType::RegistrationNotFound(_)) => (),
+            Err(err) => return Err(err)
+        };
+
         
         //? Prepare the required data value
         let registration_entry = RegistrationTable::new(email.to_string());
@@ -84,11 +311,38 @@ impl Database {
         }
     }
 
+    pub async fn refresh_registration(&self, email: &str) -> Result<RegistrationTable, ErrorType> {
+        //? Issue new tokens on the existing registration, this also restarts the confirmation step
+        let query_result = self.registration.find_one_and_update(doc! {
+            "email": email
+        }, doc! {
+            "$set": {
+                "confirmation_token": generate_token(),
+                "setup_token": generate_token(),
+                "confirmed": false,
+                "expires_at": RegistrationTable::confirmation_expiry()
+            }
+        }).sort(doc! { "created_at": -1 }).return_document(ReturnDocument::After).await;
+
+        match query_result {
+            Ok(res) => {
+                match res {
+                    Some(registration_data) => Ok(registration_data),
+                    None => Err(ErrorType::RegistrationNotFound(None))
+                }
+            },
+            Err(err) => {
+                println!("There's an error when trying to refresh registration data. Error: {}", err);
+                Err(ErrorType::UnknownError(Some(err.to_string())))
+            }
+        }
+    }
+
     pub async fn get_confirmation_data(&self, target_id: &ObjectId, confirmation_token: &str) -> Result<RegistrationTable, ErrorType> {
         //? Create query to find the confirmation data based on it's ID and Confirmation Token
         println!("[Get Confirmation Data] Target ID: {}", target_id);
         let query_result = self.registration.find_one(doc! {
-            "_id": target_id.clone(),
+            "_id": target_id,
             "confirmation_token": confirmation_token.to_string()
         }).await;
 
@@ -107,16 +361,22 @@ impl Database {
             }
         };
 
-        //? Update verification data
-        match self.registration.update_one(doc! {
-            "_id": target_id.clone(),
+        if registration_data.expires_at < DateTime::now() {
+            return Err(ErrorType::TokenExpired(None));
+        }
+
+        //? Update verification data, the setup token is valid from now on
+        match self.registration.find_one_and_update(doc! {
+            "_id": target_id,
             "confirmation_token": confirmation_token.to_string()
         }, doc! {
             "$set": {
-                "confirmed": true
+                "confirmed": true,
+                "expires_at": RegistrationTable::setup_expiry()
             }
-        }).await {
-            Ok(_) => Ok(registration_data),
+        }).return_document(ReturnDocument::After).await {
+            Ok(Some(res)) => Ok(res),
+            Ok(None) => Err(ErrorType::Unauthorized(None)),
             Err(err) => {
                 println!("There's an error when trying to update registration data. Error: {}", err);
                 return Err(ErrorType::UnknownError(Some(err.to_string())));
@@ -128,8 +388,9 @@ impl Database {
         //? Create query to find the confirmation data based on it's ID and Confirmation Token
         println!("[Setup Account] Target ID: {}", target_id);
         let query_result = self.registration.find_one(doc! {
-            "_id": target_id.clone(),
-            "setup_token": setup_token.to_string()
+            "_id": target_id,
+            "setup_token": setup_token.to_string(),
+            "confirmed": true
         }).await;
 
 
@@ -148,34 +409,69 @@ impl Database {
             }
         };
 
+        if registration_data.expires_at < DateTime::now() {
+            return Err(ErrorType::TokenExpired(None));
+        }
 
-        //? Verify there's no duplicates
-        let duplicated_data: Option<User> = match self.user.find_one(doc! {
-            "username": username.to_string(),
-            "email": registration_data.email.clone()
-        }).await {
-            Ok(user) => user,
+
+        //? Duplicate usernames and emails are caught by the unique indexes when the user is inserted below
+        let hashed_password: String = hash_password(password).await?;
+
+        let user_data: User = User::new(username.to_string(), registration_data.email.clone(), hashed_password);
+
+        //? Consuming the registration and creating the account go together, a failed insert keeps the setup token usable
+        let mut transaction = match self.client.start_session().await {
+            Ok(res) => res,
             Err(err) => {
-                println!("There's an error when trying to find user for duplication check. Error: {}", err.to_string());
+                println!("There's an error when trying to start database session. Error: {}", err);
                 return Err(ErrorType::UnknownError(Some(err.to_string())));
             }
         };
 
-        match duplicated_data {
-            Some(_) => {
-                return Err(ErrorType::DuplicatesFound(None))
-            },
-            None => ()
-        };
+        let transaction_result: Result<bool, mongodb::error::Error> = async {
+            transaction.start_transaction().await?;
 
-        
-        //? Create user account
-        let user_data: User = User::new(username.to_string(), registration_data.email.clone(), password.to_string());
-        let user_creation_result = self.user.insert_one(&user_data).await;
+            //? Consume the registration, so the setup token can only create one account
+            let consumed = self.registration.find_one_and_delete(doc! {
+                "_id": target_id,
+                "setup_token": setup_token.to_string()
+            }).session(&mut transaction).await?;
+
+            if consumed.is_none() {
+                transaction.abort_transaction().await?;
+                return Ok(false);
+            }
+
+            //? Create user account
+            self.user.insert_one(&user_data).session(&mut transaction).await?;
+
+            transaction.commit_transaction().await?;
+            Ok(true)
+        }.await;
 
-        match user_creation_result {
-            Ok(_) => Ok(user_data),
-            Err(err) => Err(ErrorType::UnknownError(Some(err.to_string())))
+        match transaction_result {
+            Ok(true) => Ok(user_data),
+            Ok(false) => Err(ErrorType::Unauthorized(None)),
+            Err(err) if is_duplicate_key_error(&err) => Err(ErrorType::DuplicatesFound(None)),
+            Err(err) => {
+                println!("There's an error when trying to create user account. Error: {}", err);
+                Err(ErrorType::UnknownError(Some(err.to_string())))
+            }
+        }
+    }
+
+    pub async fn delete_expired_registrations(&self) -> Result<u64, ErrorType> {
+        match self.registration.delete_many(doc! {
+            "$or": [
+                { "expires_at": { "$lt": DateTime::now() } },
+                { "expires_at": { "$exists": false } }
+            ]
+        }).await {
+            Ok(res) => Ok(res.deleted_count),
+            Err(err) => {
+                println!("There's an error when trying to delete expired registrations. Error: {}", err);
+                Err(ErrorType::UnknownError(Some(err.to_string())))
+            }
         }
     }
 
@@ -189,80 +485,187 @@ impl Database {
 
 
         //? Get the user data and checks the password
-        match query_result {
+        let mut user_data: User = match query_result {
             Ok(res) => {
                 match res {
-                    Some(user_data) => {
-                        if user_data.password.eq(password) {
-                            return Ok(user_data);
-                        }
-                        
-                        Err(ErrorType::Unauthorized(None))
-                    },
-                    None => Err(ErrorType::UserNotFound(None))
+                    Some(user_data) => user_data,
+                    None => return Err(ErrorType::UserNotFound(None))
                 }
             },
-            Err(err) => Err(ErrorType::UnknownError(Some(err.to_string())))
+            Err(err) => return Err(ErrorType::UnknownError(Some(err.to_string())))
+        };
+
+        match verify_password(password, &user_data.password).await {
+            PasswordVerification::Valid => Ok(user_data),
+            PasswordVerification::ValidNeedsRehash => {
+                //? Legacy plaintext (or outdated) password, upgrade it while we still have the raw password
+                let hashed_password: String = match hash_password(password).await {
+                    Ok(res) => res,
+                    Err(_) => return Ok(user_data)
+                };
+
+                match self.user.update_one(doc! {
+                    "_id": user_data.id
+                }, doc! {
+                    "$set": {
+                        "password": hashed_password.clone()
+                    }
+                }).await {
+                    Ok(_) => user_data.password = hashed_password,
+                    Err(err) => println!("There's an error when trying to rehash user password. Error: {}", err)
+                };
+
+                Ok(user_data)
+            },
+            PasswordVerification::Invalid => Err(ErrorType::Unauthorized(None))
         }
     }
 
     pub async fn initialize_device(&self, device_key: &str, device_pass: &str) -> Result<Device, ErrorType> {
-        match self.device.update_one(doc! {
-            "device_key": device_key,
-            "device_pass": device_pass
-        }, doc! {
-            "$set": {
-                "last_online": DateTime::now(),
-                "status": 0
+        let device_data: Device = self.verify_device_key_pass(device_key, device_pass).await?;
+
+        self.mark_device_online(&device_data.id, "initialization").await
+    }
+
+    pub async fn create_device(&self, device_name: &str, owner_id: &ObjectId) -> Result<(Device, String), ErrorType> {
+        //? The raw pass is only handed out once, the database keeps its hash
+        let device_pass: String = generate_long_token();
+        let device_data = Device::new(device_name.to_string(), *owner_id, hash_token(&device_pass));
+        match self.device.insert_one(&device_data).await {
+            Ok(_) => Ok((device_data, device_pass)),
+            Err(err) => {
+                println!("There's an error when trying to insert device data. Error: {}", err.to_string());
+                Err(ErrorType::UnknownError(None))
             }
+        }
+    }
+
+    pub async fn get_owned_device(&self, device_id: &ObjectId, owner_id: &ObjectId) -> Result<Device, ErrorType> {
+        let device_data: Device = match self.device.find_one(doc! {
+            "_id": device_id
         }).await {
-            Ok(res) => {
-                match res.matched_count {
-                    0 => {
-                        Err(ErrorType::DeviceNotFound(None))
-                    },
-                    _ => {
-                        let device_data = match self.device.find_one(doc! {
-                            "device_key": device_key,
-                            "device_pass": device_pass
-                        }).await {
-                            Ok(res) => match res {
-                                Some(data) => data,
-                                None => {
-                                    return Err(ErrorType::DeviceNotFound(None));
-                                }
-                            },
-                            Err(err) => {
-                                println!("There's an error when trying to get device data. Error: {}", err.to_string());
-                                return Err(ErrorType::UnknownError(None));
-                            }
-                        };
-
-                        Ok(device_data)
-                    }
+            Ok(Some(res)) => res,
+            Ok(None) => return Err(ErrorType::DeviceNotFound(None)),
+            Err(err) => {
+                println!("There's an error when trying to get device data. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        if device_data.owner_id != *owner_id {
+            return Err(ErrorType::Unauthorized(None));
+        }
+
+        Ok(device_data)
+    }
+
+    pub async fn list_devices(&self, owner_id: &ObjectId, status: Option<DeviceStatus>, name: Option<&str>, page: u64, per_page: u64) -> Result<(Vec<Device>, u64), ErrorType> {
+        //? Build the filter, the owner is always part of it
+        let mut filter = doc! { "owner_id": owner_id };
+        if let Some(status) = status {
+            filter.insert("status", i32::from(status));
+        }
+        if let Some(name) = name {
+            filter.insert("device_name", doc! { "$regex": regex::escape(name), "$options": "i" });
+        }
+
+        let total: u64 = match self.device.count_documents(filter.clone()).await {
+            Ok(res) => res,
+            Err(err) => {
+                println!("There's an error when trying to count device data. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        let devices: Vec<Device> = match self.device.find(filter)
+            .sort(doc! { "created_at": -1 })
+            .skip(page.saturating_sub(1) * per_page)
+            .limit(per_page as i64)
+            .await {
+            Ok(cursor) => match cursor.try_collect().await {
+                Ok(res) => res,
+                Err(err) => {
+                    println!("There's an error when trying to read device data. Error: {}", err);
+                    return Err(ErrorType::UnknownError(Some(err.to_string())));
                 }
             },
             Err(err) => {
-                println!("There's an error when trying to update device data. Error: {}", err.to_string());
+                println!("There's an error when trying to get device data. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        Ok((devices, total))
+    }
+
+    pub async fn rename_device(&self, device_id: &ObjectId, owner_id: &ObjectId, device_name: &str) -> Result<Device, ErrorType> {
+        self.get_owned_device(device_id, owner_id).await?;
+
+        match self.device.find_one_and_update(doc! {
+            "_id": device_id,
+            "owner_id": owner_id
+        }, doc! {
+            "$set": { "device_name": device_name }
+        }).return_document(ReturnDocument::After).await {
+            Ok(Some(res)) => Ok(res),
+            Ok(None) => Err(ErrorType::DeviceNotFound(None)),
+            Err(err) => {
+                println!("There's an error when trying to update device data. Error: {}", err);
                 Err(ErrorType::UnknownError(Some(err.to_string())))
             }
         }
     }
 
-    pub async fn create_device(&self, device_name: &str, user_email: &str) -> Result<Device, ErrorType> {
-        let device_data = Device::new(device_name.to_string(), user_email.to_string());
-        match self.device.insert_one(&device_data).await {
-            Ok(_) => Ok(device_data),
+    //? Returns the topics of the deleted controllables so they can be unsubscribed
+    pub async fn delete_device(&self, device_id: &ObjectId, owner_id: &ObjectId) -> Result<Vec<String>, ErrorType> {
+        self.get_owned_device(device_id, owner_id).await?;
+
+        //? The device's controllables and their command log are useless without it, remove them together
+        let mut transaction = match self.client.start_session().await {
+            Ok(res) => res,
             Err(err) => {
-                println!("There's an error when trying to insert device data. Error: {}", err.to_string());
-                Err(ErrorType::UnknownError(None))
+                println!("There's an error when trying to start database session. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
             }
-        }
+        };
+
+        let transaction_result: Result<Vec<String>, mongodb::error::Error> = async {
+            transaction.start_transaction().await?;
+
+            let topic_names = self.controllable.distinct("topic_name", doc! { "device_id": device_id }).session(&mut transaction).await?;
+            self.controllable.delete_many(doc! { "device_id": device_id }).session(&mut transaction).await?;
+            self.command.delete_many(doc! { "device_id": device_id }).session(&mut transaction).await?;
+            self.telemetry_hourly.delete_many(doc! { "device_id": device_id }).session(&mut transaction).await?;
+            self.telemetry_daily.delete_many(doc! { "device_id": device_id }).session(&mut transaction).await?;
+            self.device_status_change.delete_many(doc! { "device_id": device_id }).session(&mut transaction).await?;
+            self.device.delete_one(doc! { "_id": device_id, "owner_id": owner_id }).session(&mut transaction).await?;
+
+            transaction.commit_transaction().await?;
+            Ok(topic_names.iter().filter_map(|topic_name| topic_name.as_str().map(String::from)).collect())
+        }.await;
+
+        let topic_names: Vec<String> = match transaction_result {
+            Ok(res) => res,
+            Err(err) => {
+                println!("There's an error when trying to delete device {}. Error: {}", device_id, err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        self.delete_telemetry(doc! { "meta.device_id": device_id }).await;
+
+        Ok(topic_names)
     }
 
-    pub async fn create_controllable(&self, device_id: &str, controllable_name: &str, controllable_category: ControllableCategory, user_email: &str) -> Result<Controllable, ErrorType> {
-        //? Get and Verify the controllable category
+    pub async fn create_controllable(&self, device_id: &ObjectId, controllable_name: &str, controllable_category: ControllableCategory, controllable_config: Option<ControllableConfig>, owner_id: &ObjectId) -> Result<Controllable, ErrorType> {
+        let controllable_config: ControllableConfig = resolve_controllable_config(controllable_category, controllable_config)?;
+
+        //? Make sure the device exists and belongs to the requesting user
+        self.get_owned_device(device_id, owner_id).await?;
+
+        //? Controllable names only have to be unique within their device
         let unexpected_controllable_data = self.controllable.find_one(doc! {
+            "device_id": device_id,
             "controllable_name": controllable_name
         }).await;
 
@@ -282,20 +685,12 @@ impl Database {
         };
 
         //? Create the controllable_data
-        let object_device_id = ObjectId::parse_str(device_id);
-        let object_device_id = match object_device_id {
-            Ok(res) => res,
-            Err(err) => {
-                println!("There's an error when trying to parse device_id");
-                return Err(ErrorType::UnknownError(Some(err.to_string())));
-            },
-        };
-        
-        let controllable_data = Controllable::new(controllable_name.to_string(), controllable_category, object_device_id, user_email.to_string());
+        let controllable_data = Controllable::new(controllable_name.to_string(), controllable_category, controllable_config, *device_id, *owner_id);
         let create_result = self.controllable.insert_one(&controllable_data).await;
 
         match create_result {
             Ok(_) => Ok(controllable_data),
+            Err(err) if is_duplicate_key_error(&err) => Err(ErrorType::DuplicatesFound(None)),
             Err(err) => {
                 println!("There's an error when trying to create controllable data");
                 Err(ErrorType::UnknownError(Some(err.to_string())))
@@ -303,85 +698,1181 @@ impl Database {
         }
     }
 
-    pub async fn get_controllable(&self, controllable_name: &str) -> Result<Controllable, ErrorType> {
-        //? Get the controllable data
-        let controllable_data: Result<Option<Controllable>, _> = self.controllable.find_one(doc! {
-            "controllable_name": controllable_name
-        }).await;
-        
-        // Check if there's an error
-        let controllable_data: Option<Controllable> = match controllable_data {
-            Ok(res) => res,
+    pub async fn get_owned_controllable(&self, controllable_id: &ObjectId, owner_id: &ObjectId) -> Result<Controllable, ErrorType> {
+        let controllable_data: Controllable = match self.controllable.find_one(doc! {
+            "_id": controllable_id
+        }).await {
+            Ok(Some(res)) => res,
+            Ok(None) => return Err(ErrorType::ControllableNotFound(None)),
             Err(err) => {
-                println!("There's an error when trying to get controllable data. Error: {}", err.to_string());
-                return Err(ErrorType::UnknownError(Some(err.to_string())))
+                println!("There's an error when trying to get controllable data. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
             }
         };
 
-        // Check if there's no controllable found
-        let controllable_data: Controllable = match controllable_data {
-            Some(res) => res,
-            None => {
-                return Err(ErrorType::ControllableNotFound(None));
-            }
-        };
+        if controllable_data.owner_id != *owner_id {
+            return Err(ErrorType::Unauthorized(None));
+        }
 
         Ok(controllable_data)
     }
 
-    pub async fn verify_device_key_pass(&self, device_key: &str, device_pass: &str) -> Result<Device, ErrorType> {
-        let device_data = match self.device.find_one(doc! {
-            "device_key": device_key,
-            "device_pass": device_pass
-        }).await {
-            Ok(res) => res,
+    pub async fn list_controllables(&self, device_id: &ObjectId, owner_id: &ObjectId) -> Result<Vec<Controllable>, ErrorType> {
+        self.get_owned_device(device_id, owner_id).await?;
+
+        match self.controllable.find(doc! {
+            "device_id": device_id,
+            "owner_id": owner_id
+        }).sort(doc! { "created_at": 1 }).await {
+            Ok(cursor) => match cursor.try_collect().await {
+                Ok(res) => Ok(res),
+                Err(err) => {
+                    println!("There's an error when trying to read controllable data. Error: {}", err);
+                    Err(ErrorType::UnknownError(Some(err.to_string())))
+                }
+            },
             Err(err) => {
-                println!("There's an error when trying to get device data. Error: {}", err.to_string());
-                return Err(ErrorType::UnknownError(Some(err.to_string())));
+                println!("There's an error when trying to get controllable data. Error: {}", err);
+                Err(ErrorType::UnknownError(Some(err.to_string())))
             }
-        };
-
-        match device_data {
-            Some(res) => Ok(res),
-            None => Err(ErrorType::DeviceNotFound(None)),
         }
     }
 
-    pub async fn create_otp_login(&self, email: &str) -> Result<LoginOTPTable, ErrorType> {
-        println!("[Insert Registration] Email: {}", email);
-        
-        //? Prepare the required data value
-        let login_otp_entry = LoginOTPTable::new(email.to_string());
+    pub async fn update_controllable(&self, controllable_id: &ObjectId, owner_id: &ObjectId, controllable_name: Option<&str>, controllable_category: Option<ControllableCategory>, controllable_config: Option<ControllableConfig>) -> Result<Controllable, ErrorType> {
+        let controllable_data: Controllable = self.get_owned_controllable(controllable_id, owner_id).await?;
 
-        //? Create a query to insert the new registration data
-        let query_result: Result<mongodb::results::InsertOneResult, mongodb::error::Error> = self.otp.insert_one(&login_otp_entry).await;
+        //? A new category without a config resets it to the new category's default
+        let category_changed: bool = controllable_category.is_some_and(|category| category != controllable_data.category);
+        let controllable_config: Option<ControllableConfig> = match (controllable_config, category_changed) {
+            (None, false) => None,
+            (controllable_config, _) => Some(resolve_controllable_config(controllable_category.unwrap_or(controllable_data.category), controllable_config)?)
+        };
 
+        let mut changes = doc! {};
+        if let Some(controllable_name) = controllable_name {
+            if controllable_name != controllable_data.controllable_name {
+                match self.controllable.find_one(doc! {
+                    "device_id": controllable_data.device_id,
+                    "controllable_name": controllable_name
+                }).await {
+                    Ok(Some(_)) => return Err(ErrorType::DuplicatesFound(None)),
+                    Ok(None) => (),
+                    Err(err) => {
+                        println!("There's an error when trying to get controllable data. Error: {}", err);
+                        return Err(ErrorType::UnknownError(Some(err.to_string())));
+                    }
+                };
+            }
 
-        //? Check if there's error. if not, send the ID
-        match query_result {
-            Ok(_) => Ok(login_otp_entry),
-            Err(error) => Err(ErrorType::UnknownError(Some(error.to_string())))
+            changes.insert("controllable_name", controllable_name);
+        }
+        if let Some(controllable_category) = controllable_category {
+            match (mongodb::bson::to_bson(&controllable_category), mongodb::bson::to_bson(&controllable_category.direction())) {
+                (Ok(category), Ok(direction)) => {
+                    changes.insert("category", category);
+                    changes.insert("direction", direction);
+                },
+                (Err(err), _) | (_, Err(err)) => return Err(ErrorType::UnknownError(Some(err.to_string())))
+            };
+        }
+        if let Some(controllable_config) = controllable_config {
+            match mongodb::bson::to_bson(&controllable_config) {
+                Ok(res) => changes.insert("config", res),
+                Err(err) => return Err(ErrorType::UnknownError(Some(err.to_string())))
+            };
+            changes.insert("unit", controllable_config.unit());
         }
-    }
 
-    pub async fn verify_otp_data(&self, email: &str, token: &str) -> Result<(), ErrorType> {
+        if changes.is_empty() {
+            return Ok(controllable_data);
+        }
+
+        match self.controllable.find_one_and_update(doc! {
+            "_id": controllable_id,
+            "owner_id": owner_id
+        }, doc! {
+            "$set": changes
+        }).return_document(ReturnDocument::After).await {
+            Ok(Some(res)) => Ok(res),
+            Ok(None) => Err(ErrorType::ControllableNotFound(None)),
+            Err(err) if is_duplicate_key_error(&err) => Err(ErrorType::DuplicatesFound(None)),
+            Err(err) => {
+                println!("There's an error when trying to update controllable data. Error: {}", err);
+                Err(ErrorType::UnknownError(Some(err.to_string())))
+            }
+        }
+    }
+
+    pub async fn delete_controllable(&self, controllable_id: &ObjectId, owner_id: &ObjectId) -> Result<Controllable, ErrorType> {
+        let controllable_data: Controllable = self.get_owned_controllable(controllable_id, owner_id).await?;
+
+        //? The controllable's command log and rollups go with it, like `delete_device` does for its children
+        let mut transaction = match self.client.start_session().await {
+            Ok(res) => res,
+            Err(err) => {
+                println!("There's an error when trying to start database session. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        let transaction_result: Result<(), mongodb::error::Error> = async {
+            transaction.start_transaction().await?;
+
+            self.command.delete_many(doc! { "controllable_id": controllable_id }).session(&mut transaction).await?;
+            self.telemetry_hourly.delete_many(doc! { "controllable_id": controllable_id }).session(&mut transaction).await?;
+            self.telemetry_daily.delete_many(doc! { "controllable_id": controllable_id }).session(&mut transaction).await?;
+            self.controllable.delete_one(doc! { "_id": controllable_id, "owner_id": owner_id }).session(&mut transaction).await?;
+
+            transaction.commit_transaction().await
+        }.await;
+
+        if let Err(err) = transaction_result {
+            println!("There's an error when trying to delete controllable {}. Error: {}", controllable_id, err);
+            return Err(ErrorType::UnknownError(Some(err.to_string())));
+        }
+
+        self.delete_telemetry(doc! { "meta.controllable_id": controllable_id }).await;
+
+        Ok(controllable_data)
+    }
+
+    pub async fn get_controllable(&self, device_id: &ObjectId, controllable_name: &str) -> Result<Controllable, ErrorType> {
+        //? Get the controllable data, only the given device's controllables are visible
+        let controllable_data: Result<Option<Controllable>, _> = self.controllable.find_one(doc! {
+            "device_id": device_id,
+            "controllable_name": controllable_name
+        }).await;
+        
+        // Check if there's an error
+        let controllable_data: Option<Controllable> = match controllable_data {
+            Ok(res) => res,
+            Err(err) => {
+                println!("There's an error when trying to get controllable data. Error: {}", err.to_string());
+                return Err(ErrorType::UnknownError(Some(err.to_string())))
+            }
+        };
+
+        // Check if there's no controllable found
+        let controllable_data: Controllable = match controllable_data {
+            Some(res) => res,
+            None => {
+                return Err(ErrorType::ControllableNotFound(None));
+            }
+        };
+
+        Ok(controllable_data)
+    }
+
+    pub async fn list_topic_names(&self) -> Result<Vec<String>, ErrorType> {
+        let controllable_col: Collection<Document> = self.controllable.clone_with_type::<Document>();
+
+        let controllable_documents: Vec<Document> = match controllable_col.find(doc! {}).projection(doc! { "_id": 0, "topic_name": 1 }).await {
+            Ok(cursor) => match cursor.try_collect().await {
+                Ok(res) => res,
+                Err(err) => {
+                    println!("There's an error when trying to read controllable topics. Error: {}", err);
+                    return Err(ErrorType::UnknownError(Some(err.to_string())));
+                }
+            },
+            Err(err) => {
+                println!("There's an error when trying to get controllable topics. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        Ok(controllable_documents.iter().filter_map(|document| document.get_str("topic_name").ok().map(String::from)).collect())
+    }
+
+    //? Stores the latest value seen on a controllable's topic and marks its device as online
+    pub async fn record_controllable_value(&self, topic_name: &str, value: &str) -> Result<Controllable, ErrorType> {
+        let now: DateTime = DateTime::now();
+
+        let controllable_data: Controllable = match self.controllable.find_one_and_update(doc! {
+            "topic_name": topic_name
+        }, doc! {
+            "$set": {
+                "last_value": value,
+                "last_value_at": now
+            }
+        }).return_document(ReturnDocument::After).await {
+            Ok(Some(res)) => res,
+            Ok(None) => return Err(ErrorType::ControllableNotFound(None)),
+            Err(err) => {
+                println!("There's an error when trying to update controllable value. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        self.mark_device_online(&controllable_data.device_id, "mqtt").await?;
+
+        Ok(controllable_data)
+    }
+
+    pub async fn insert_command(&self, command_data: CommandTable) -> Result<CommandTable, ErrorType> {
+        match self.command.insert_one(&command_data).await {
+            Ok(_) => Ok(command_data),
+            Err(err) => {
+                println!("There's an error when trying to insert command data. Error: {}", err);
+                Err(ErrorType::UnknownError(Some(err.to_string())))
+            }
+        }
+    }
+
+    //? Command topics resolve to the controllable they command
+    pub async fn get_controllable_by_topic(&self, topic_name: &str) -> Result<Controllable, ErrorType> {
+        let topic_name: &str = topic_name.strip_suffix(COMMAND_TOPIC_SUFFIX).unwrap_or(topic_name);
+
+        match self.controllable.find_one(doc! {
+            "topic_name": topic_name
+        }).await {
+            Ok(Some(res)) => Ok(res),
+            Ok(None) => Err(ErrorType::ControllableNotFound(None)),
+            Err(err) => {
+                println!("There's an error when trying to get controllable data. Error: {}", err);
+                Err(ErrorType::UnknownError(Some(err.to_string())))
+            }
+        }
+    }
+
+    pub async fn get_device_by_key(&self, device_key: &str) -> Result<Device, ErrorType> {
+        let device_data: Device = match self.device.find_one(doc! {
+            "$or": [
+                { "device_key": device_key },
+                { "previous_credentials.device_key": device_key }
+            ]
+        }).await {
+            Ok(Some(res)) => res,
+            Ok(None) => return Err(ErrorType::DeviceNotFound(None)),
+            Err(err) => {
+                println!("There's an error when trying to get device data. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        if device_data.device_key == device_key {
+            return Ok(device_data);
+        }
+
+        match &device_data.previous_credentials {
+            Some(previous) if previous.expires_at > DateTime::now() => Ok(device_data),
+            _ => Err(ErrorType::DeviceNotFound(None))
+        }
+    }
+
+    pub async fn verify_device_key_pass(&self, device_key: &str, device_pass: &str) -> Result<Device, ErrorType> {
+        //? Look the device up by its key only, the pass is compared against the stored hash
+        let device_data = match self.device.find_one(doc! {
+            "$or": [
+                { "device_key": device_key },
+                { "previous_credentials.device_key": device_key }
+            ]
+        }).await {
+            Ok(res) => res,
+            Err(err) => {
+                println!("There's an error when trying to get device data. Error: {}", err.to_string());
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        let device_data: Device = match device_data {
+            Some(res) => res,
+            None => return Err(ErrorType::DeviceNotFound(None)),
+        };
+
+        let device_pass_hash: String = hash_token(device_pass);
+
+        if device_data.device_key == device_key {
+            if !device_data.device_pass_hash.is_empty() {
+                return match bool::from(device_data.device_pass_hash.as_bytes().ct_eq(device_pass_hash.as_bytes())) {
+                    true => Ok(device_data),
+                    false => Err(ErrorType::DeviceNotFound(None))
+                };
+            }
+
+            //? Legacy device, upgrade the plaintext pass to a hash
+            let legacy_match = match &device_data.device_pass {
+                Some(legacy_pass) => bool::from(legacy_pass.as_bytes().ct_eq(device_pass.as_bytes())),
+                None => false
+            };
+
+            if !legacy_match {
+                return Err(ErrorType::DeviceNotFound(None));
+            }
+
+            return match self.device.find_one_and_update(doc! {
+                "_id": device_data.id
+            }, doc! {
+                "$set": { "device_pass_hash": device_pass_hash },
+                "$unset": { "device_pass": "" }
+            }).return_document(ReturnDocument::After).await {
+                Ok(Some(res)) => Ok(res),
+                Ok(None) => Err(ErrorType::DeviceNotFound(None)),
+                Err(err) => {
+                    println!("There's an error when trying to hash device pass. Error: {}", err);
+                    Err(ErrorType::UnknownError(Some(err.to_string())))
+                }
+            };
+        }
+
+        //? Old credentials are only accepted during the grace window of a rotation
+        match &device_data.previous_credentials {
+            Some(previous) if previous.expires_at > DateTime::now() && bool::from(previous.device_pass_hash.as_bytes().ct_eq(device_pass_hash.as_bytes())) => Ok(device_data),
+            _ => Err(ErrorType::DeviceNotFound(None))
+        }
+    }
+
+    pub async fn rotate_device_credentials(&self, device_id: &ObjectId, owner_id: &ObjectId, grace_period_seconds: u64) -> Result<(Device, String), ErrorType> {
+        let device_data: Device = self.get_owned_device(device_id, owner_id).await?;
+
+        let current_pass_hash: String = match &device_data.device_pass {
+            Some(legacy_pass) if device_data.device_pass_hash.is_empty() => hash_token(legacy_pass),
+            _ => device_data.device_pass_hash.clone()
+        };
+
+        //? Keep the old pair around only if a grace window is requested
+        let max_grace_period_seconds: u64 = env_or("DEVICE_CREDENTIALS_MAX_GRACE_SECONDS", 604800);
+        let previous_credentials = match grace_period_seconds.min(max_grace_period_seconds) {
+            0 => None,
+            grace_period_seconds => Some(PreviousDeviceCredentials {
+                device_key: device_data.device_key.clone(),
+                device_pass_hash: current_pass_hash,
+                expires_at: DateTime::now().saturating_add_duration(Duration::from_secs(grace_period_seconds))
+            })
+        };
+
+        let previous_credentials = match mongodb::bson::to_bson(&previous_credentials) {
+            Ok(res) => res,
+            Err(err) => return Err(ErrorType::UnknownError(Some(err.to_string())))
+        };
+
+        let device_key: String = generate_long_token();
+        let device_pass: String = generate_long_token();
+
+        match self.device.find_one_and_update(doc! {
+            "_id": device_id,
+            "owner_id": owner_id
+        }, doc! {
+            "$set": {
+                "device_key": device_key,
+                "device_pass_hash": hash_token(&device_pass),
+                "previous_credentials": previous_credentials
+            },
+            "$unset": { "device_pass": "" }
+        }).return_document(ReturnDocument::After).await {
+            Ok(Some(res)) => Ok((res, device_pass)),
+            Ok(None) => Err(ErrorType::DeviceNotFound(None)),
+            Err(err) => {
+                println!("There's an error when trying to rotate device credentials. Error: {}", err);
+                Err(ErrorType::UnknownError(Some(err.to_string())))
+            }
+        }
+    }
+
+    async fn check_otp_lockout(&self, email: &str) -> Result<(), ErrorType> {
+        let attempt_data = match self.otp_attempt.find_one(doc! {
+            "email": email,
+            "locked_until": { "$gt": DateTime::now() }
+        }).await {
+            Ok(res) => res,
+            Err(err) => {
+                println!("There's an error when trying to get OTP attempt data. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        match attempt_data {
+            Some(_) => Err(ErrorType::TooManyAttempts(None)),
+            None => Ok(())
+        }
+    }
+
+    async fn record_failed_otp_attempt(&self, email: &str) -> Result<(), ErrorType> {
+        let max_attempts: i32 = env_or("OTP_MAX_ATTEMPTS", 5);
+        let lockout_seconds: u64 = env_or("OTP_LOCKOUT_SECONDS", 900);
+        let expires_at: DateTime = DateTime::now().saturating_add_duration(Duration::from_secs(lockout_seconds));
+
+        //? Increase the counter, the row expires after a lockout window without any failure. Mongo's TTL purge can run
+        //? late, so a row already past `expires_at` starts counting from scratch instead of carrying its old count.
+        let now: DateTime = DateTime::now();
+        let attempt_data = match self.otp_attempt.find_one_and_update(doc! {
+            "email": email
+        }, vec![doc! {
+            "$set": {
+                "failed_attempts": { "$cond": [{ "$gt": ["$expires_at", now] }, { "$add": ["$failed_attempts", 1] }, 1] },
+                "locked_until": { "$cond": [{ "$gt": ["$locked_until", now] }, "$locked_until", null] },
+                "expires_at": expires_at
+            }
+        }]).upsert(true).return_document(ReturnDocument::After).await {
+            Ok(res) => res,
+            Err(err) => {
+                println!("There's an error when trying to update OTP attempt data. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        let failed_attempts: i32 = attempt_data.map(|data| data.failed_attempts).unwrap_or(1);
+        if failed_attempts < max_attempts {
+            return Err(ErrorType::Unauthorized(None));
+        }
+
+        //? Too many wrong guesses, lock the email and burn the current code
+        if let Err(err) = self.otp_attempt.update_one(doc! {
+            "email": email
+        }, doc! {
+            "$set": { "locked_until": expires_at }
+        }).await {
+            println!("There's an error when trying to lock OTP login. Error: {}", err);
+        }
+
+        if let Err(err) = self.otp.delete_many(doc! { "email": email }).await {
+            println!("There's an error when trying to delete OTP data. Error: {}", err);
+        }
+
+        Err(ErrorType::TooManyAttempts(None))
+    }
+
+    pub async fn create_otp_login(&self, email: &str) -> Result<LoginOTPTable, ErrorType> {
+        println!("[Insert OTP Login] Email: {}", email);
+
+        self.check_otp_lockout(email).await?;
+
+        //? Invalidate every code issued before this one
+        if let Err(err) = self.otp.delete_many(doc! { "email": email }).await {
+            println!("There's an error when trying to invalidate old OTP data. Error: {}", err);
+            return Err(ErrorType::UnknownError(Some(err.to_string())));
+        }
+        
+        //? Prepare the required data value
+        let login_otp_entry = LoginOTPTable::new(email.to_string());
+
+        //? Create a query to insert the new registration data
+        let query_result: Result<mongodb::results::InsertOneResult, mongodb::error::Error> = self.otp.insert_one(&login_otp_entry).await;
+
+
+        //? Check if there's error. if not, send the ID
+        match query_result {
+            Ok(_) => Ok(login_otp_entry),
+            Err(error) => Err(ErrorType::UnknownError(Some(error.to_string())))
+        }
+    }
+
+    pub async fn verify_otp_data(&self, email: &str, token: &str) -> Result<(), ErrorType> {
+        self.check_otp_lockout(email).await?;
+
         //? Get the otp data first
         let login_otp_data = self.otp.find_one(doc! {
-            "email": email,
-            "confirmation_token": token
-        }).await;
+            "email": email
+        }).sort(doc! { "created_at": -1 }).await;
 
-        match login_otp_data {
+        let login_otp_data: LoginOTPTable = match login_otp_data {
             Ok(res) => {
                 match res {
-                    Some(_) => Ok(()),
-                    None => Err(ErrorType::Unauthorized(None)),
+                    Some(data) => data,
+                    None => return Err(ErrorType::Unauthorized(None)),
                 }
             },
             Err(err) => {
                 println!("There's an error when trying to get OTP data. Error: {}", err.to_string());
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        //? The TTL monitor only runs periodically, so expiry has to be checked here as well
+        if login_otp_data.expires_at < DateTime::now() {
+            return Err(ErrorType::TokenExpired(None));
+        }
+
+        if !bool::from(login_otp_data.confirmation_token.as_bytes().ct_eq(token.as_bytes())) {
+            return self.record_failed_otp_attempt(email).await;
+        }
+
+        //? Consume the code, only one request may succeed with it
+        match self.otp.find_one_and_delete(doc! {
+            "_id": login_otp_data.id
+        }).await {
+            Ok(Some(_)) => (),
+            Ok(None) => return Err(ErrorType::Unauthorized(None)),
+            Err(err) => {
+                println!("There's an error when trying to consume OTP data. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        if let Err(err) = self.otp_attempt.delete_one(doc! { "email": email }).await {
+            println!("There's an error when trying to reset OTP attempt data. Error: {}", err);
+        }
+
+        Ok(())
+    }
+
+    pub async fn create_session(&self, user_id: &ObjectId) -> Result<(SessionTable, String), ErrorType> {
+        self.insert_session(ObjectId::new(), user_id).await
+    }
+
+    async fn insert_session(&self, family_id: ObjectId, user_id: &ObjectId) -> Result<(SessionTable, String), ErrorType> {
+        //? Only the hash is stored, the raw refresh token lives in the client's cookie
+        let refresh_token: String = generate_long_token();
+        let session_data = SessionTable::new(family_id, *user_id, hash_token(&refresh_token));
+
+        match self.session.insert_one(&session_data).await {
+            Ok(_) => Ok((session_data, refresh_token)),
+            Err(err) => {
+                println!("There's an error when trying to insert session data. Error: {}", err);
+                Err(ErrorType::UnknownError(Some(err.to_string())))
+            }
+        }
+    }
+
+    pub async fn rotate_session(&self, refresh_token: &str) -> Result<(SessionTable, String), ErrorType> {
+        let refresh_token_hash: String = hash_token(refresh_token);
+
+        let session_data: SessionTable = match self.session.find_one(doc! {
+            "refresh_token_hash": &refresh_token_hash
+        }).await {
+            Ok(Some(res)) => res,
+            Ok(None) => return Err(ErrorType::Unauthorized(None)),
+            Err(err) => {
+                println!("There's an error when trying to get session data. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        if session_data.revoked {
+            return Err(ErrorType::Unauthorized(None));
+        }
+
+        if session_data.expires_at < DateTime::now() {
+            return Err(ErrorType::TokenExpired(None));
+        }
+
+        //? Mark the token as used, a token that was already used means it has been stolen
+        let claimed_session = match self.session.find_one_and_update(doc! {
+            "_id": session_data.id,
+            "used": false,
+            "revoked": false
+        }, doc! {
+            "$set": { "used": true }
+        }).await {
+            Ok(res) => res,
+            Err(err) => {
+                println!("There's an error when trying to update session data. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        if claimed_session.is_none() {
+            println!("[Rotate Session] Refresh token reuse detected, revoking session family {}", session_data.family_id);
+            self.revoke_session_family(&session_data.family_id).await?;
+            return Err(ErrorType::Unauthorized(None));
+        }
+
+        self.insert_session(session_data.family_id, &session_data.user_id).await
+    }
+
+    pub async fn get_session_family_id(&self, refresh_token: &str) -> Result<ObjectId, ErrorType> {
+        match self.session.find_one(doc! {
+            "refresh_token_hash": hash_token(refresh_token)
+        }).await {
+            Ok(Some(res)) => Ok(res.family_id),
+            Ok(None) => Err(ErrorType::Unauthorized(None)),
+            Err(err) => {
+                println!("There's an error when trying to get session data. Error: {}", err);
+                Err(ErrorType::UnknownError(Some(err.to_string())))
+            }
+        }
+    }
+
+    pub async fn is_session_active(&self, family_id: &ObjectId) -> Result<bool, ErrorType> {
+        match self.session.find_one(doc! {
+            "family_id": family_id,
+            "revoked": false,
+            "expires_at": { "$gt": DateTime::now() }
+        }).await {
+            Ok(res) => Ok(res.is_some()),
+            Err(err) => {
+                println!("There's an error when trying to get session data. Error: {}", err);
+                Err(ErrorType::UnknownError(Some(err.to_string())))
+            }
+        }
+    }
+
+    pub async fn revoke_session_family(&self, family_id: &ObjectId) -> Result<(), ErrorType> {
+        match self.session.update_many(doc! {
+            "family_id": family_id
+        }, doc! {
+            "$set": { "revoked": true }
+        }).await {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                println!("There's an error when trying to revoke session family. Error: {}", err);
+                Err(ErrorType::UnknownError(Some(err.to_string())))
+            }
+        }
+    }
+
+    pub async fn revoke_user_sessions(&self, user_id: &ObjectId) -> Result<(), ErrorType> {
+        match self.session.update_many(doc! {
+            "user_id": user_id
+        }, doc! {
+            "$set": { "revoked": true }
+        }).await {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                println!("There's an error when trying to revoke user sessions. Error: {}", err);
+                Err(ErrorType::UnknownError(Some(err.to_string())))
+            }
+        }
+    }
+
+    pub async fn create_password_reset(&self, user_id: &ObjectId) -> Result<(PasswordResetTable, String), ErrorType> {
+        //? Only the latest requested token stays usable
+        if let Err(err) = self.password_reset.delete_many(doc! { "user_id": user_id }).await {
+            println!("There's an error when trying to invalidate old password reset data. Error: {}", err);
+            return Err(ErrorType::UnknownError(Some(err.to_string())));
+        }
+
+        let reset_token: String = generate_long_token();
+        let password_reset_data = PasswordResetTable::new(*user_id, hash_token(&reset_token));
+
+        match self.password_reset.insert_one(&password_reset_data).await {
+            Ok(_) => Ok((password_reset_data, reset_token)),
+            Err(err) => {
+                println!("There's an error when trying to insert password reset data. Error: {}", err);
+                Err(ErrorType::UnknownError(Some(err.to_string())))
+            }
+        }
+    }
+
+    pub async fn reset_password(&self, reset_token: &str, new_password: &str) -> Result<User, ErrorType> {
+        let hashed_password: String = hash_password(new_password).await?;
+
+        //? Consume the token, so it can only be used once
+        let password_reset_data: PasswordResetTable = match self.password_reset.find_one_and_delete(doc! {
+            "token_hash": hash_token(reset_token)
+        }).await {
+            Ok(Some(res)) => res,
+            Ok(None) => return Err(ErrorType::Unauthorized(None)),
+            Err(err) => {
+                println!("There's an error when trying to consume password reset data. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        if password_reset_data.expires_at < DateTime::now() {
+            return Err(ErrorType::TokenExpired(None));
+        }
+
+        let user_data: User = match self.user.find_one_and_update(doc! {
+            "_id": password_reset_data.user_id
+        }, doc! {
+            "$set": { "password": hashed_password }
+        }).return_document(ReturnDocument::After).await {
+            Ok(Some(res)) => res,
+            Ok(None) => return Err(ErrorType::UserNotFound(None)),
+            Err(err) => {
+                println!("There's an error when trying to update user password. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        //? Anyone holding the old password may have logged in already
+        self.revoke_user_sessions(&user_data.id).await?;
+
+        Ok(user_data)
+    }
+
+    pub async fn update_username(&self, user_id: &ObjectId, username: &str) -> Result<User, ErrorType> {
+        //? Username is used to login, the unique index keeps it that way
+        match self.user.find_one_and_update(doc! {
+            "_id": user_id
+        }, doc! {
+            "$set": { "username": username }
+        }).return_document(ReturnDocument::After).await {
+            Ok(Some(res)) => Ok(res),
+            Ok(None) => Err(ErrorType::UserNotFound(None)),
+            Err(err) if is_duplicate_key_error(&err) => Err(ErrorType::DuplicatesFound(None)),
+            Err(err) => {
+                println!("There's an error when trying to update username. Error: {}", err);
+                Err(ErrorType::UnknownError(Some(err.to_string())))
+            }
+        }
+    }
+
+    pub async fn change_password(&self, user_data: &User, current_password: &str, new_password: &str, current_session_family_id: &ObjectId) -> Result<(), ErrorType> {
+        if let PasswordVerification::Invalid = verify_password(current_password, &user_data.password).await {
+            return Err(ErrorType::Unauthorized(None));
+        }
+
+        let hashed_password: String = hash_password(new_password).await?;
+
+        match self.user.update_one(doc! {
+            "_id": user_data.id
+        }, doc! {
+            "$set": { "password": hashed_password }
+        }).await {
+            Ok(_) => (),
+            Err(err) => {
+                println!("There's an error when trying to update user password. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        //? Keep the session that made the change, log out every other one
+        match self.session.update_many(doc! {
+            "user_id": user_data.id,
+            "family_id": { "$ne": current_session_family_id }
+        }, doc! {
+            "$set": { "revoked": true }
+        }).await {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                println!("There's an error when trying to revoke user sessions. Error: {}", err);
+                Err(ErrorType::UnknownError(Some(err.to_string())))
+            }
+        }
+    }
+
+    async fn is_email_taken(&self, email: &str) -> Result<bool, ErrorType> {
+        match self.user.find_one(doc! {
+            "email": email
+        }).await {
+            Ok(res) => Ok(res.is_some()),
+            Err(err) => {
+                println!("There's an error when trying to find user for duplication check. Error: {}", err);
+                Err(ErrorType::UnknownError(Some(err.to_string())))
+            }
+        }
+    }
+
+    pub async fn create_email_change(&self, user_data: &User, password: &str, new_email: &str) -> Result<(EmailChangeTable, String), ErrorType> {
+        if let PasswordVerification::Invalid = verify_password(password, &user_data.password).await {
+            return Err(ErrorType::Unauthorized(None));
+        }
+
+        if self.is_email_taken(new_email).await? {
+            return Err(ErrorType::DuplicatesFound(None));
+        }
+
+        //? Only the latest requested change stays usable
+        if let Err(err) = self.email_change.delete_many(doc! { "user_id": user_data.id }).await {
+            println!("There's an error when trying to invalidate old email change data. Error: {}", err);
+            return Err(ErrorType::UnknownError(Some(err.to_string())));
+        }
+
+        let confirmation_token: String = generate_long_token();
+        let email_change_data = EmailChangeTable::new(user_data.id, new_email.to_string(), hash_token(&confirmation_token));
+
+        match self.email_change.insert_one(&email_change_data).await {
+            Ok(_) => Ok((email_change_data, confirmation_token)),
+            Err(err) => {
+                println!("There's an error when trying to insert email change data. Error: {}", err);
+                Err(ErrorType::UnknownError(Some(err.to_string())))
+            }
+        }
+    }
+
+    pub async fn confirm_email_change(&self, user_data: &User, confirmation_token: &str) -> Result<User, ErrorType> {
+        //? Consume the token, so it can only be used once
+        let email_change_data: EmailChangeTable = match self.email_change.find_one_and_delete(doc! {
+            "user_id": user_data.id,
+            "token_hash": hash_token(confirmation_token)
+        }).await {
+            Ok(Some(res)) => res,
+            Ok(None) => return Err(ErrorType::Unauthorized(None)),
+            Err(err) => {
+                println!("There's an error when trying to consume email change data. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        if email_change_data.expires_at < DateTime::now() {
+            return Err(ErrorType::TokenExpired(None));
+        }
+
+        //? Devices and controllables reference their owner by id, only the user itself changes.
+        //? Someone may have registered the address since the change was requested, the unique index catches that.
+        match self.user.update_one(doc! { "_id": user_data.id }, doc! {
+            "$set": { "email": &email_change_data.new_email }
+        }).await {
+            Ok(_) => (),
+            Err(err) if is_duplicate_key_error(&err) => return Err(ErrorType::DuplicatesFound(None)),
+            Err(err) => {
+                println!("There's an error when trying to change user email. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        if let Err(err) = self.otp.delete_many(doc! { "email": &user_data.email }).await {
+            println!("There's an error when trying to delete OTP data. Error: {}", err);
+        }
+
+        self.get_user_by_id(&user_data.id).await
+    }
+
+    pub async fn schedule_user_deletion(&self, user_data: &User, password: &str) -> Result<(), ErrorType> {
+        //? Re-authenticate, a stolen session alone must not be enough to delete the account
+        if let PasswordVerification::Invalid = verify_password(password, &user_data.password).await {
+            return Err(ErrorType::Unauthorized(None));
+        }
+
+        match self.user.update_one(doc! {
+            "_id": user_data.id
+        }, doc! {
+            "$set": { "deleted_at": DateTime::now() }
+        }).await {
+            Ok(_) => (),
+            Err(err) => {
+                println!("There's an error when trying to schedule user deletion. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        self.revoke_user_sessions(&user_data.id).await
+    }
+
+    pub async fn cancel_user_deletion(&self, user_id: &ObjectId) -> Result<(), ErrorType> {
+        match self.user.update_one(doc! {
+            "_id": user_id
+        }, doc! {
+            "$set": { "deleted_at": null }
+        }).await {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                println!("There's an error when trying to cancel user deletion. Error: {}", err);
                 Err(ErrorType::UnknownError(Some(err.to_string())))
             }
         }
     }
+
+    //? Returns how many accounts were purged and the topics of their controllables
+    pub async fn purge_deleted_users(&self) -> Result<(u64, Vec<String>), ErrorType> {
+        let grace_days: u64 = env_or("ACCOUNT_DELETION_GRACE_DAYS", 14);
+        let purge_before: DateTime = DateTime::from_millis(DateTime::now().timestamp_millis() - (grace_days * 86400 * 1000) as i64);
+
+        let users: Vec<User> = match self.user.find(doc! {
+            "deleted_at": { "$lt": purge_before }
+        }).await {
+            Ok(cursor) => match cursor.try_collect().await {
+                Ok(res) => res,
+                Err(err) => {
+                    println!("There's an error when trying to read deleted users. Error: {}", err);
+                    return Err(ErrorType::UnknownError(Some(err.to_string())));
+                }
+            },
+            Err(err) => {
+                println!("There's an error when trying to get deleted users. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        let mut purged_count: u64 = 0;
+        let mut purged_topic_names: Vec<String> = Vec::new();
+        for user_data in users.iter() {
+            if let Ok(topic_names) = self.delete_user_cascade(user_data).await {
+                purged_count += 1;
+                purged_topic_names.extend(topic_names);
+            }
+        }
+
+        Ok((purged_count, purged_topic_names))
+    }
+
+    //? Returns the topics of the deleted controllables so they can be unsubscribed
+    pub async fn delete_user_cascade(&self, user_data: &User) -> Result<Vec<String>, ErrorType> {
+        //? Everything owned by the user goes away together, or nothing does
+        let mut transaction = match self.client.start_session().await {
+            Ok(res) => res,
+            Err(err) => {
+                println!("There's an error when trying to start database session. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        let transaction_result: Result<Vec<String>, mongodb::error::Error> = async {
+            transaction.start_transaction().await?;
+
+            let topic_names = self.controllable.distinct("topic_name", doc! { "owner_id": user_data.id }).session(&mut transaction).await?;
+            self.controllable.delete_many(doc! { "owner_id": user_data.id }).session(&mut transaction).await?;
+            self.device.delete_many(doc! { "owner_id": user_data.id }).session(&mut transaction).await?;
+            self.otp.delete_many(doc! { "email": &user_data.email }).session(&mut transaction).await?;
+            self.otp_attempt.delete_many(doc! { "email": &user_data.email }).session(&mut transaction).await?;
+            self.registration.delete_many(doc! { "email": &user_data.email }).session(&mut transaction).await?;
+            self.session.delete_many(doc! { "user_id": user_data.id }).session(&mut transaction).await?;
+            self.password_reset.delete_many(doc! { "user_id": user_data.id }).session(&mut transaction).await?;
+            self.email_change.delete_many(doc! { "user_id": user_data.id }).session(&mut transaction).await?;
+            self.command.delete_many(doc! { "user_id": user_data.id }).session(&mut transaction).await?;
+            self.telemetry_hourly.delete_many(doc! { "owner_id": user_data.id }).session(&mut transaction).await?;
+            self.telemetry_daily.delete_many(doc! { "owner_id": user_data.id }).session(&mut transaction).await?;
+            self.device_status_change.delete_many(doc! { "owner_id": user_data.id }).session(&mut transaction).await?;
+            self.user.delete_one(doc! { "_id": user_data.id }).session(&mut transaction).await?;
+
+            transaction.commit_transaction().await?;
+            Ok(topic_names.iter().filter_map(|topic_name| topic_name.as_str().map(String::from)).collect())
+        }.await;
+
+        let topic_names: Vec<String> = match transaction_result {
+            Ok(res) => res,
+            Err(err) => {
+                println!("There's an error when trying to delete user {}. Error: {}", user_data.id, err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        self.delete_telemetry(doc! { "meta.owner_id": user_data.id }).await;
+
+        Ok(topic_names)
+    }
+
+    //? Time-series collections can't be written inside a transaction, so readings are dropped after their owner is gone
+    async fn delete_telemetry(&self, filter: Document) {
+        if let Err(err) = self.telemetry.delete_many(filter).await {
+            println!("There's an error when trying to delete telemetry data. Error: {}", err);
+        }
+    }
+
+    pub async fn insert_telemetry(&self, device_data: &Device, readings: Vec<(String, f64, DateTime)>) -> Result<u64, ErrorType> {
+        let controllables: Vec<Controllable> = match self.controllable.find(doc! {
+            "device_id": device_data.id
+        }).await {
+            Ok(cursor) => match cursor.try_collect().await {
+                Ok(res) => res,
+                Err(err) => {
+                    println!("There's an error when trying to read controllable data. Error: {}", err);
+                    return Err(ErrorType::UnknownError(Some(err.to_string())));
+                }
+            },
+            Err(err) => {
+                println!("There's an error when trying to get controllable data. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        //? The whole batch is rejected if any reading points to a controllable the device doesn't have
+        let mut telemetry_data: Vec<TelemetryTable> = Vec::with_capacity(readings.len());
+        for (controllable_name, value, timestamp) in &readings {
+            match controllables.iter().find(|controllable| &controllable.controllable_name == controllable_name) {
+                Some(controllable) => telemetry_data.push(TelemetryTable::new(controllable, *value, *timestamp)),
+                None => return Err(ErrorType::ControllableNotFound(Some(controllable_name.clone())))
+            };
+        }
+
+        if telemetry_data.is_empty() {
+            return Ok(0);
+        }
+
+        let inserted_count: u64 = match self.telemetry.insert_many(&telemetry_data).await {
+            Ok(res) => res.inserted_ids.len() as u64,
+            Err(err) => {
+                println!("There's an error when trying to insert telemetry data. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        //? Mirror the newest reading of each controllable, same as values coming through the broker
+        for controllable in &controllables {
+            let latest_reading = telemetry_data.iter().filter(|reading| reading.meta.controllable_id == controllable.id).max_by_key(|reading| reading.timestamp);
+
+            if let Some(latest_reading) = latest_reading {
+                if controllable.last_value_at.is_some_and(|last_value_at| last_value_at > latest_reading.timestamp) {
+                    continue;
+                }
+
+                if let Err(err) = self.controllable.update_one(doc! {
+                    "_id": controllable.id
+                }, doc! {
+                    "$set": {
+                        "last_value": latest_reading.value.to_string(),
+                        "last_value_at": latest_reading.timestamp
+                    }
+                }).await {
+                    println!("There's an error when trying to update controllable value. Error: {}", err);
+                }
+            }
+        }
+
+        //? The readings are stored already, a failed status update is only logged so the device doesn't resend them
+        self.mark_device_online(&device_data.id, "telemetry").await.ok();
+
+        Ok(inserted_count)
+    }
+
+    //? Raw readings only go back to the device's raw cutoff, so 1h and 1d buckets before it come from the rollups.
+    //? Raw buckets fill in whatever the rollups don't have yet, e.g. yesterday right before the next rollup run.
+    pub async fn get_controllable_history(&self, controllable_data: &Controllable, owner_data: &User, from: DateTime, to: DateTime, bucket_size: HistoryBucketSize) -> Result<Vec<HistoryBucket>, ErrorType> {
+        //? Readings are sorted first so `$last` is the newest reading of each bucket
+        let raw_pipeline = vec![
+            doc! { "$match": { "meta.controllable_id": controllable_data.id, "timestamp": { "$gte": from, "$lt": to } } },
+            doc! { "$sort": { "timestamp": 1 } },
+            doc! { "$group": {
+                "_id": { "$dateTrunc": { "date": "$timestamp", "unit": bucket_size.unit() } },
+                "min": { "$min": "$value" },
+                "max": { "$max": "$value" },
+                "avg": { "$avg": "$value" },
+                "last": { "$last": "$value" },
+                "count": { "$sum": 1_i64 }
+            } },
+            doc! { "$sort": { "_id": 1 } },
+            doc! { "$project": { "_id": 0, "timestamp": "$_id", "min": 1, "max": 1, "avg": 1, "last": 1, "count": 1 } }
+        ];
+        let raw_buckets: Vec<HistoryBucket> = aggregate_history(&self.telemetry, raw_pipeline).await?;
+
+        let rollup_collection: &Collection<TelemetryRollupTable> = match bucket_size {
+            HistoryBucketSize::Minute => return Ok(raw_buckets),
+            HistoryBucketSize::Hour => &self.telemetry_hourly,
+            HistoryBucketSize::Day => &self.telemetry_daily
+        };
+
+        let device_data: Device = self.get_owned_device(&controllable_data.device_id, &controllable_data.owner_id).await?;
+        //? The owner is already loaded by the caller, only the device has to be read
+        let mut owner_policies: HashMap<ObjectId, Option<RetentionPolicy>> = HashMap::from([(owner_data.id, owner_data.retention)]);
+        let policy: RetentionPolicy = self.get_device_retention(&device_data, &mut owner_policies).await;
+
+        let (rollup_start, rollup_end) = retention::history_rollup_window(DateTime::now().timestamp_millis(), policy.raw_days, (from.timestamp_millis(), to.timestamp_millis()), bucket_size.millis());
+
+        let mut buckets: BTreeMap<i64, HistoryBucket> = raw_buckets.into_iter().map(|bucket| (bucket.timestamp.timestamp_millis(), bucket)).collect();
+        if rollup_start < rollup_end {
+            let rollup_pipeline = vec![
+                doc! { "$match": {
+                    "controllable_id": controllable_data.id,
+                    "bucket_start": { "$gte": DateTime::from_millis(rollup_start), "$lt": DateTime::from_millis(rollup_end) }
+                } },
+                doc! { "$sort": { "bucket_start": 1 } },
+                doc! { "$group": {
+                    "_id": "$bucket_start",
+                    "min": { "$min": "$min" },
+                    "max": { "$max": "$max" },
+                    "sum": { "$sum": "$sum" },
+                    "count": { "$sum": "$count" },
+                    "last": { "$last": "$last" }
+                } },
+                doc! { "$project": { "_id": 0, "timestamp": "$_id", "min": 1, "max": 1, "avg": { "$divide": ["$sum", "$count"] }, "last": 1, "count": 1 } }
+            ];
+
+            //? A rollup was computed from complete raw data, so it wins over what's left of the raw readings
+            for bucket in aggregate_history(rollup_collection, rollup_pipeline).await? {
+                buckets.insert(bucket.timestamp.timestamp_millis(), bucket);
+            }
+        }
+
+        Ok(buckets.into_values().collect())
+    }
+
+    pub async fn list_controllable_readings(&self, controllable_id: &ObjectId, from: DateTime, to: DateTime, page: u64, per_page: u64) -> Result<(Vec<HistoryReading>, u64), ErrorType> {
+        let filter = doc! { "meta.controllable_id": controllable_id, "timestamp": { "$gte": from, "$lt": to } };
+
+        let total: u64 = match self.telemetry.count_documents(filter.clone()).await {
+            Ok(res) => res,
+            Err(err) => {
+                println!("There's an error when trying to count telemetry data. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        let readings: Vec<HistoryReading> = match self.telemetry.clone_with_type::<HistoryReading>().find(filter)
+            .projection(doc! { "_id": 0, "timestamp": 1, "value": 1 })
+            .sort(doc! { "timestamp": 1 })
+            .skip(page.saturating_sub(1) * per_page)
+            .limit(per_page as i64)
+            .await {
+            Ok(cursor) => match cursor.try_collect().await {
+                Ok(res) => res,
+                Err(err) => {
+                    println!("There's an error when trying to read telemetry data. Error: {}", err);
+                    return Err(ErrorType::UnknownError(Some(err.to_string())));
+                }
+            },
+            Err(err) => {
+                println!("There's an error when trying to get telemetry data. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        Ok((readings, total))
+    }
+
+    pub async fn rotate_mqtt_credentials(&self, user_data: &User) -> Result<User, ErrorType> {
+        //? Devices still using the old pair get a short window to pick up the new one
+        let overlap_seconds: u64 = env_or("MQTT_CREDENTIALS_OVERLAP_SECONDS", 300);
+        let previous_credentials = PreviousMqttCredentials {
+            mqtt_user: user_data.mqtt_user.clone(),
+            mqtt_pass: user_data.mqtt_pass.clone(),
+            expires_at: DateTime::now().saturating_add_duration(Duration::from_secs(overlap_seconds))
+        };
+
+        let previous_credentials = match mongodb::bson::to_bson(&previous_credentials) {
+            Ok(res) => res,
+            Err(err) => return Err(ErrorType::UnknownError(Some(err.to_string())))
+        };
+
+        match self.user.find_one_and_update(doc! {
+            "_id": user_data.id
+        }, doc! {
+            "$set": {
+                "mqtt_user": generate_long_token(),
+                "mqtt_pass": generate_long_token(),
+                "previous_mqtt_credentials": previous_credentials
+            }
+        }).return_document(ReturnDocument::After).await {
+            Ok(Some(res)) => Ok(res),
+            Ok(None) => Err(ErrorType::UserNotFound(None)),
+            Err(err) => {
+                println!("There's an error when trying to rotate MQTT credentials. Error: {}", err);
+                Err(ErrorType::UnknownError(Some(err.to_string())))
+            }
+        }
+    }
+
+    pub async fn get_user_by_mqtt_user(&self, mqtt_user: &str) -> Result<User, ErrorType> {
+        let user_data: User = match self.user.find_one(doc! {
+            "$or": [
+                { "mqtt_user": mqtt_user },
+                { "previous_mqtt_credentials.mqtt_user": mqtt_user }
+            ]
+        }).await {
+            Ok(Some(res)) => res,
+            Ok(None) => return Err(ErrorType::UserNotFound(None)),
+            Err(err) => {
+                println!("There's an error when trying to get user data. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        if user_data.mqtt_user == mqtt_user {
+            return Ok(user_data);
+        }
+
+        match &user_data.previous_mqtt_credentials {
+            Some(previous) if previous.expires_at > DateTime::now() => Ok(user_data),
+            _ => Err(ErrorType::UserNotFound(None))
+        }
+    }
+
+    pub async fn verify_mqtt_credentials(&self, mqtt_user: &str, mqtt_pass: &str) -> Result<User, ErrorType> {
+        let user_data: User = match self.user.find_one(doc! {
+            "$or": [
+                { "mqtt_user": mqtt_user },
+                { "previous_mqtt_credentials.mqtt_user": mqtt_user }
+            ]
+        }).await {
+            Ok(Some(res)) => res,
+            Ok(None) => return Err(ErrorType::UserNotFound(None)),
+            Err(err) => {
+                println!("There's an error when trying to get user data. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        if user_data.mqtt_user == mqtt_user {
+            return match bool::from(user_data.mqtt_pass.as_bytes().ct_eq(mqtt_pass.as_bytes())) {
+                true => Ok(user_data),
+                false => Err(ErrorType::Unauthorized(None))
+            };
+        }
+
+        //? Old credentials are refused as soon as the overlap window is over
+        match &user_data.previous_mqtt_credentials {
+            Some(previous) if previous.expires_at > DateTime::now() && bool::from(previous.mqtt_pass.as_bytes().ct_eq(mqtt_pass.as_bytes())) => Ok(user_data),
+            _ => Err(ErrorType::Unauthorized(None))
+        }
+    }
 }
\ No newline at end of file
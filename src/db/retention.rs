@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+
+use futures::TryStreamExt;
+use mongodb::{bson::{doc, oid::ObjectId, DateTime, Document}, options::ReturnDocument};
+
+use crate::types::{db_model::{Device, RetentionPolicy, User}, error::ErrorType};
+
+use super::Database;
+
+const HOUR_MILLIS: i64 = 3_600_000;
+const DAY_MILLIS: i64 = 86_400_000;
+
+//? Start of the bucket `millis` falls into
+fn bucket_floor(millis: i64, bucket_millis: i64) -> i64 {
+    millis - millis.rem_euclid(bucket_millis)
+}
+
+//? Start of the first bucket that begins at or after `millis`
+fn bucket_ceil(millis: i64, bucket_millis: i64) -> i64 {
+    bucket_floor(millis + bucket_millis - 1, bucket_millis)
+}
+
+//? Hourly and daily windows a rollup run recomputes, only complete buckets are rolled up
+pub fn rollup_windows(now_millis: i64, lookback_hours: i64) -> ((i64, i64), (i64, i64)) {
+    let hourly_end: i64 = bucket_floor(now_millis, HOUR_MILLIS);
+    let hourly_start: i64 = hourly_end - lookback_hours * HOUR_MILLIS;
+
+    ((hourly_start, hourly_end), (bucket_floor(hourly_start, DAY_MILLIS), bucket_floor(now_millis, DAY_MILLIS)))
+}
+
+//? Raw cutoff of a device and the hourly window left to recompute for it. The cutoff never passes what's been
+//? rolled up, and an hour it already cut into would be replaced by a partial bucket so recomputing starts after it.
+pub fn device_rollup_window(now_millis: i64, raw_days: u32, (hourly_start, hourly_end): (i64, i64)) -> (i64, (i64, i64)) {
+    let raw_cutoff: i64 = (now_millis - raw_days as i64 * DAY_MILLIS).min(hourly_end);
+
+    (raw_cutoff, (hourly_start.max(bucket_ceil(raw_cutoff, HOUR_MILLIS)), hourly_end))
+}
+
+//? Buckets of a history query read from the rollups, the one the raw cutoff falls into has lost part of its
+//? raw readings so it belongs to the rollups too
+pub fn history_rollup_window(now_millis: i64, raw_days: u32, (from_millis, to_millis): (i64, i64), bucket_millis: i64) -> (i64, i64) {
+    let raw_cutoff: i64 = now_millis - raw_days as i64 * DAY_MILLIS;
+
+    (bucket_floor(from_millis, bucket_millis), bucket_ceil(raw_cutoff, bucket_millis).min(to_millis))
+}
+
+//? `None` removes the policy so the next level up applies again
+fn retention_update(retention: Option<RetentionPolicy>) -> Result<Document, ErrorType> {
+    let retention: RetentionPolicy = match retention {
+        Some(res) => res,
+        None => return Ok(doc! { "$unset": { "retention": "" } })
+    };
+
+    if let Err(message) = retention.validate() {
+        return Err(ErrorType::InvalidConfig(Some(message)));
+    }
+
+    match mongodb::bson::to_bson(&retention) {
+        Ok(res) => Ok(doc! { "$set": { "retention": res } }),
+        Err(err) => Err(ErrorType::UnknownError(Some(err.to_string())))
+    }
+}
+
+impl Database {
+    pub async fn update_user_retention(&self, user_id: &ObjectId, retention: Option<RetentionPolicy>) -> Result<User, ErrorType> {
+        match self.user.find_one_and_update(doc! {
+            "_id": user_id
+        }, retention_update(retention)?).return_document(ReturnDocument::After).await {
+            Ok(Some(res)) => Ok(res),
+            Ok(None) => Err(ErrorType::UserNotFound(None)),
+            Err(err) => {
+                println!("There's an error when trying to update user retention. Error: {}", err);
+                Err(ErrorType::UnknownError(Some(err.to_string())))
+            }
+        }
+    }
+
+    pub async fn update_device_retention(&self, device_id: &ObjectId, owner_id: &ObjectId, retention: Option<RetentionPolicy>) -> Result<Device, ErrorType> {
+        let update: Document = retention_update(retention)?;
+        self.get_owned_device(device_id, owner_id).await?;
+
+        match self.device.find_one_and_update(doc! {
+            "_id": device_id,
+            "owner_id": owner_id
+        }, update).return_document(ReturnDocument::After).await {
+            Ok(Some(res)) => Ok(res),
+            Ok(None) => Err(ErrorType::DeviceNotFound(None)),
+            Err(err) => {
+                println!("There's an error when trying to update device retention. Error: {}", err);
+                Err(ErrorType::UnknownError(Some(err.to_string())))
+            }
+        }
+    }
+
+    //? Device policy first, then the owner's, then the server defaults. Owners are looked up once per `owner_policies`.
+    pub async fn get_device_retention(&self, device_data: &Device, owner_policies: &mut HashMap<ObjectId, Option<RetentionPolicy>>) -> RetentionPolicy {
+        if let Some(retention) = device_data.retention {
+            return retention;
+        }
+
+        let owner_policy: Option<RetentionPolicy> = match owner_policies.get(&device_data.owner_id) {
+            Some(res) => *res,
+            None => {
+                let owner_policy: Option<RetentionPolicy> = self.get_user_by_id(&device_data.owner_id).await.ok().and_then(|user_data| user_data.retention);
+                owner_policies.insert(device_data.owner_id, owner_policy);
+                owner_policy
+            }
+        };
+
+        owner_policy.unwrap_or_else(RetentionPolicy::default_policy)
+    }
+
+    //? Compacts raw readings into hourly buckets and hourly buckets into daily ones, then drops the raw readings
+    //? the device's policy doesn't keep anymore. The last `TELEMETRY_ROLLUP_LOOKBACK_HOURS` are recomputed on
+    //? every run so late readings still make it in. Returns how many devices were processed.
+    pub async fn run_telemetry_rollup(&self) -> Result<u64, ErrorType> {
+        let now_millis: i64 = DateTime::now().timestamp_millis();
+        let lookback_hours: i64 = RetentionPolicy::rollup_lookback_hours() as i64;
+
+        let (hourly_window, daily_window) = rollup_windows(now_millis, lookback_hours);
+
+        let mut devices = match self.device.find(doc! {}).await {
+            Ok(res) => res,
+            Err(err) => {
+                println!("There's an error when trying to get device data. Error: {}", err);
+                return Err(ErrorType::UnknownError(Some(err.to_string())));
+            }
+        };
+
+        let mut owner_policies: HashMap<ObjectId, Option<RetentionPolicy>> = HashMap::new();
+        let mut processed_count: u64 = 0;
+
+        loop {
+            let device_data: Device = match devices.try_next().await {
+                Ok(Some(res)) => res,
+                Ok(None) => break,
+                Err(err) => {
+                    println!("There's an error when trying to read device data. Error: {}", err);
+                    return Err(ErrorType::UnknownError(Some(err.to_string())));
+                }
+            };
+
+            let policy: RetentionPolicy = self.get_device_retention(&device_data, &mut owner_policies).await;
+            let (raw_cutoff, device_hourly_window) = device_rollup_window(now_millis, policy.raw_days, hourly_window);
+
+            if let Err(err) = self.rollup_device_telemetry(&device_data.id, &policy, device_hourly_window, daily_window).await {
+                println!("There's an error when trying to roll up telemetry of device {}. Error: {}", device_data.id, err);
+                continue;
+            }
+
+            //? Never drop raw readings that haven't been rolled up yet, filtering time-series deletes on `timestamp` needs MongoDB 7.0
+            if let Err(err) = self.telemetry.delete_many(doc! {
+                "meta.device_id": device_data.id,
+                "timestamp": { "$lt": DateTime::from_millis(raw_cutoff) }
+            }).await {
+                println!("There's an error when trying to expire telemetry of device {}. Error: {}", device_data.id, err);
+            }
+
+            processed_count += 1;
+        }
+
+        Ok(processed_count)
+    }
+
+    async fn rollup_device_telemetry(&self, device_id: &ObjectId, policy: &RetentionPolicy, hourly_window: (i64, i64), daily_window: (i64, i64)) -> Result<(), mongodb::error::Error> {
+        let rollup_projection = |retention_days: u32| doc! {
+            "_id": 0,
+            "controllable_id": "$_id.controllable_id",
+            "bucket_start": "$_id.bucket_start",
+            "device_id": 1,
+            "owner_id": 1,
+            "min": 1,
+            "max": 1,
+            "sum": 1,
+            "count": 1,
+            "last": 1,
+            "last_at": 1,
+            "avg": { "$divide": ["$sum", "$count"] },
+            "expires_at": { "$dateAdd": { "startDate": "$_id.bucket_start", "unit": "day", "amount": retention_days as i64 } }
+        };
+        let merge_into = |collection_name: &str| doc! {
+            "$merge": {
+                "into": collection_name,
+                "on": ["controllable_id", "bucket_start"],
+                "whenMatched": "replace",
+                "whenNotMatched": "insert"
+            }
+        };
+
+        //? Raw readings into hourly buckets, sorted first so `$last` is the newest reading
+        let hourly_pipeline = vec![
+            doc! { "$match": {
+                "meta.device_id": device_id,
+                "timestamp": { "$gte": DateTime::from_millis(hourly_window.0), "$lt": DateTime::from_millis(hourly_window.1) }
+            } },
+            doc! { "$sort": { "timestamp": 1 } },
+            doc! { "$group": {
+                "_id": {
+                    "controllable_id": "$meta.controllable_id",
+                    "bucket_start": { "$dateTrunc": { "date": "$timestamp", "unit": "hour" } }
+                },
+                "device_id": { "$first": "$meta.device_id" },
+                "owner_id": { "$first": "$meta.owner_id" },
+                "min": { "$min": "$value" },
+                "max": { "$max": "$value" },
+                "sum": { "$sum": "$value" },
+                "count": { "$sum": 1_i64 },
+                "last": { "$last": "$value" },
+                "last_at": { "$last": "$timestamp" }
+            } },
+            doc! { "$project": rollup_projection(policy.hourly_days) },
+            merge_into(self.telemetry_hourly.name())
+        ];
+        self.telemetry.aggregate(hourly_pipeline).await?.try_collect::<Vec<Document>>().await?;
+
+        //? Hourly buckets into daily ones, so days stay complete even after their raw readings expired
+        let daily_pipeline = vec![
+            doc! { "$match": {
+                "device_id": device_id,
+                "bucket_start": { "$gte": DateTime::from_millis(daily_window.0), "$lt": DateTime::from_millis(daily_window.1) }
+            } },
+            doc! { "$sort": { "bucket_start": 1 } },
+            doc! { "$group": {
+                "_id": {
+                    "controllable_id": "$controllable_id",
+                    "bucket_start": { "$dateTrunc": { "date": "$bucket_start", "unit": "day" } }
+                },
+                "device_id": { "$first": "$device_id" },
+                "owner_id": { "$first": "$owner_id" },
+                "min": { "$min": "$min" },
+                "max": { "$max": "$max" },
+                "sum": { "$sum": "$sum" },
+                "count": { "$sum": "$count" },
+                "last": { "$last": "$last" },
+                "last_at": { "$last": "$last_at" }
+            } },
+            doc! { "$project": rollup_projection(policy.daily_days) },
+            merge_into(self.telemetry_daily.name())
+        ];
+        self.telemetry_hourly.aggregate(daily_pipeline).await?.try_collect::<Vec<Document>>().await?;
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //? 2023-11-15T00:00:00Z, a day boundary
+    const DAY: i64 = 1_700_006_400_000;
+    const MINUTE_MILLIS: i64 = 60_000;
+
+    #[test]
+    fn rounds_to_buckets() {
+        assert_eq!(bucket_floor(DAY, HOUR_MILLIS), DAY);
+        assert_eq!(bucket_floor(DAY + HOUR_MILLIS - 1, HOUR_MILLIS), DAY);
+        assert_eq!(bucket_floor(DAY + 90 * MINUTE_MILLIS, DAY_MILLIS), DAY);
+
+        assert_eq!(bucket_ceil(DAY, HOUR_MILLIS), DAY);
+        assert_eq!(bucket_ceil(DAY + 1, HOUR_MILLIS), DAY + HOUR_MILLIS);
+        assert_eq!(bucket_ceil(DAY - 1, DAY_MILLIS), DAY);
+
+        //? Before 1970 still rounds down, not towards zero
+        assert_eq!(bucket_floor(-1, HOUR_MILLIS), -HOUR_MILLIS);
+        assert_eq!(bucket_ceil(-HOUR_MILLIS + 1, HOUR_MILLIS), 0);
+    }
+
+    #[test]
+    fn rolls_up_complete_buckets_only() {
+        let now_millis: i64 = DAY + 5 * HOUR_MILLIS + 30 * MINUTE_MILLIS;
+        let ((hourly_start, hourly_end), (daily_start, daily_end)) = rollup_windows(now_millis, 48);
+
+        assert_eq!(hourly_end, DAY + 5 * HOUR_MILLIS);
+        assert_eq!(hourly_start, DAY - 43 * HOUR_MILLIS);
+        assert_eq!(daily_end, DAY);
+        //? The daily window reaches back to the start of the day the lookback begins in
+        assert_eq!(daily_start, DAY - 2 * DAY_MILLIS);
+
+        let ((_, hourly_end), (_, daily_end)) = rollup_windows(DAY, 48);
+        assert_eq!(hourly_end, DAY);
+        assert_eq!(daily_end, DAY);
+    }
+
+    #[test]
+    fn skips_hours_the_raw_cutoff_cut_into() {
+        let now_millis: i64 = DAY + 5 * HOUR_MILLIS + 30 * MINUTE_MILLIS;
+        let hourly_window: (i64, i64) = (DAY - 43 * HOUR_MILLIS, DAY + 5 * HOUR_MILLIS);
+
+        //? A long raw retention leaves the whole lookback to recompute
+        assert_eq!(device_rollup_window(now_millis, 7, hourly_window), (now_millis - 7 * DAY_MILLIS, hourly_window));
+
+        //? A day of raw readings ends mid-hour, that hour keeps the rollup it already has
+        let (raw_cutoff, (device_hourly_start, device_hourly_end)) = device_rollup_window(now_millis, 1, hourly_window);
+        assert_eq!(raw_cutoff, DAY - 19 * HOUR_MILLIS + 30 * MINUTE_MILLIS);
+        assert_eq!(device_hourly_start, DAY - 18 * HOUR_MILLIS);
+        assert_eq!(device_hourly_end, hourly_window.1);
+
+        //? Raw readings newer than the last complete hour are never dropped
+        assert_eq!(device_rollup_window(now_millis, 0, hourly_window), (hourly_window.1, (hourly_window.1, hourly_window.1)));
+    }
+
+    #[test]
+    fn reads_rollups_up_to_the_raw_cutoff() {
+        let now_millis: i64 = DAY + 5 * HOUR_MILLIS + 30 * MINUTE_MILLIS;
+        let from_millis: i64 = DAY - 10 * DAY_MILLIS + 30 * MINUTE_MILLIS;
+
+        //? Hourly buckets start at the hour `from` falls into and run through the one the cutoff cut into
+        assert_eq!(history_rollup_window(now_millis, 7, (from_millis, now_millis), HOUR_MILLIS), (DAY - 10 * DAY_MILLIS, DAY - 7 * DAY_MILLIS + 6 * HOUR_MILLIS));
+
+        //? Daily buckets round out to whole days
+        assert_eq!(history_rollup_window(now_millis, 7, (from_millis, now_millis), DAY_MILLIS), (DAY - 10 * DAY_MILLIS, DAY - 6 * DAY_MILLIS));
+
+        //? Queries ending before the cutoff are served from the rollups only
+        let to_millis: i64 = DAY - 8 * DAY_MILLIS;
+        assert_eq!(history_rollup_window(now_millis, 7, (from_millis, to_millis), HOUR_MILLIS), (DAY - 10 * DAY_MILLIS, to_millis));
+
+        //? Queries that only cover raw readings leave an empty window
+        let (rollup_start, rollup_end) = history_rollup_window(now_millis, 7, (DAY - DAY_MILLIS, now_millis), HOUR_MILLIS);
+        assert!(rollup_start >= rollup_end);
+    }
+}
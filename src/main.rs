@@ -5,11 +5,13 @@ pub mod db;
 pub mod types;
 pub mod utils;
 pub mod middlewares;
+pub mod tasks;
 
-use api::{device::{device_initialization, get_controllable}, user::{confirm_registration, create_controllable, create_device, setup_registration, user_get, user_otp_login, user_otp_verify, user_password_login, user_registration}};
+use api::{catcher::{internal_server_error, unauthorized}, device::{device_heartbeat, device_initialization, device_telemetry, get_controllable, get_controllable_config, get_controllable_v2}, mqtt::{mqtt_acl, mqtt_auth, mqtt_superuser}, user::{device_connectivity, device_uptime, update_device_retention, update_user_retention, controllable_history, send_command, rotate_device_credentials, rotate_mqtt_credentials, delete_controllable, get_user_controllable, list_controllables, update_controllable, delete_device, get_device, list_devices, update_device, cancel_delete_account, delete_account, change_email_confirm, change_email_request, change_password, confirm_registration, update_profile, password_reset_confirm, password_reset_request, resend_confirmation, create_controllable, create_device, setup_registration, user_get, user_logout, user_logout_all, user_otp_login, user_otp_verify, user_refresh, user_password_login, user_registration}};
 use db::Database;
 use dotenvy::dotenv;
 use std::env;
+use tasks::{account::run_account_purger, device::run_offline_detector, mqtt::{run_mqtt_client, MqttClient}, registration::run_registration_sweeper, telemetry::run_telemetry_rollup};
 
 // GET route
 #[get("/test")]
@@ -22,26 +24,76 @@ async fn rocket() -> _ {
     dotenv().ok();
     
     let mongodb_uri = env::var("MONGO_DB_URI").expect("Please, Specify 'MONGO_DB_URI' in your '.env' file man.... :)\n");
-    let database: Database = Database::new(mongodb_uri.as_str(), "iotconnect_system_db", "user", "registration", "device", "controllable", "otp_login").await;
+    let database: Database = Database::new(mongodb_uri.as_str(), "iotconnect_system_db", "user", "registration", "device", "controllable", "otp_login", "otp_login_attempt", "session", "password_reset", "email_change", "command", "telemetry", "telemetry_hourly", "telemetry_daily", "device_status_change").await;
+
+    database.migrate_owner_ids().await;
+
+    let (mqtt_client, mqtt_eventloop) = MqttClient::from_env();
+    if let Some(eventloop) = mqtt_eventloop {
+        tokio::spawn(run_mqtt_client(database.clone(), mqtt_client.clone(), eventloop));
+    }
+
+    tokio::spawn(run_registration_sweeper(database.clone()));
+    tokio::spawn(run_account_purger(database.clone(), mqtt_client.clone()));
+    tokio::spawn(run_telemetry_rollup(database.clone()));
+    tokio::spawn(run_offline_detector(database.clone()));
 
     rocket::build()
         .manage(database)
+        .manage(mqtt_client)
+        .register("/api/", catchers![unauthorized, internal_server_error])
         .mount("/api/", 
             routes![
                 /* User API */ 
                 index, 
                 user_registration,
+                resend_confirmation,
                 confirm_registration,
                 setup_registration,
                 user_password_login,
                 user_get,
+                update_profile,
+                update_user_retention,
+                change_password,
+                change_email_request,
+                change_email_confirm,
+                rotate_mqtt_credentials,
+                delete_account,
+                cancel_delete_account,
                 create_device,
+                list_devices,
+                get_device,
+                update_device,
+                delete_device,
+                rotate_device_credentials,
+                update_device_retention,
+                device_connectivity,
+                device_uptime,
+                list_controllables,
+                get_user_controllable,
+                update_controllable,
+                delete_controllable,
+                send_command,
+                controllable_history,
                 user_otp_login,
                 user_otp_verify,
+                user_refresh,
+                user_logout,
+                user_logout_all,
+                password_reset_request,
+                password_reset_confirm,
                 /* Device API */ 
                 device_initialization,
+                device_heartbeat,
                 create_controllable,
-                get_controllable
+                get_controllable,
+                get_controllable_v2,
+                get_controllable_config,
+                device_telemetry,
+                /* MQTT Broker API */
+                mqtt_auth,
+                mqtt_superuser,
+                mqtt_acl
             ]
         )
 }
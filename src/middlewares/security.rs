@@ -1,7 +1,13 @@
 use std::env;
 
+use mongodb::bson::oid::ObjectId;
 use rocket::request::{FromRequest, Outcome};
-use rocket::{Request, http::Status};
+use rocket::{Request, State, http::Status};
+use subtle::ConstantTimeEq;
+
+use crate::db::Database;
+use crate::types::{db_model::User, error::ErrorType};
+use crate::utils::{verify_access_token, Claims};
 
 pub struct ApiKey;
 
@@ -10,7 +16,13 @@ impl<'r> FromRequest<'r> for ApiKey {
     type Error = ();
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        if let Some(key) = request.headers().get_one("authorization") {
+        //? `authorization` may carry a user's bearer token, so the key can also be sent through `x-api-key`
+        let key = match request.headers().get_one("x-api-key") {
+            Some(key) => Some(key),
+            None => request.headers().get_one("authorization").filter(|key| !key.starts_with("Bearer "))
+        };
+
+        if let Some(key) = key {
             if key == env::var("API_KEY").expect("Please, define `API_KEY` in .env file") {
                 return Outcome::Success(ApiKey);
             }
@@ -22,3 +34,77 @@ impl<'r> FromRequest<'r> for ApiKey {
         }
     }
 }
+
+//? Only the broker's auth plugin may call the `/mqtt/*` webhooks, it sends `MQTT_WEBHOOK_SECRET` in `x-mqtt-webhook-secret`
+pub struct MqttWebhookSecret;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for MqttWebhookSecret {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        //? Without a configured secret the webhooks stay closed
+        let secret: String = match env::var("MQTT_WEBHOOK_SECRET") {
+            Ok(res) if !res.is_empty() => res,
+            _ => return Outcome::Error((Status::Unauthorized, ()))
+        };
+
+        match request.headers().get_one("x-mqtt-webhook-secret") {
+            Some(key) if bool::from(key.as_bytes().ct_eq(secret.as_bytes())) => Outcome::Success(MqttWebhookSecret),
+            _ => Outcome::Error((Status::Unauthorized, ()))
+        }
+    }
+}
+
+pub struct AuthenticatedUser {
+    pub user: User,
+    pub claims: Claims
+}
+
+async fn authenticate_user(request: &Request<'_>) -> Result<AuthenticatedUser, Status> {
+    let db = match request.guard::<&State<Database>>().await {
+        Outcome::Success(db) => db,
+        _ => return Err(Status::InternalServerError)
+    };
+
+    //? Browsers send the `user_token` cookie, other clients may use the bearer header instead
+    let token: String = match request.cookies().get("user_token") {
+        Some(cookie) => cookie.value().to_string(),
+        None => match request.headers().get_one("authorization").and_then(|header| header.strip_prefix("Bearer ")) {
+            Some(token) => token.to_string(),
+            None => return Err(Status::Unauthorized)
+        }
+    };
+
+    let claims: Claims = match verify_access_token(db, &token).await {
+        Ok(res) => res,
+        Err(ErrorType::Unauthorized(_)) => return Err(Status::Unauthorized),
+        Err(_) => return Err(Status::InternalServerError)
+    };
+
+    let user_id: ObjectId = match ObjectId::parse_str(&claims.sub) {
+        Ok(res) => res,
+        Err(_) => return Err(Status::Unauthorized)
+    };
+
+    match db.get_user_by_id(&user_id).await {
+        Ok(user) => Ok(AuthenticatedUser { user, claims }),
+        Err(ErrorType::UserNotFound(_)) => Err(Status::Unauthorized),
+        Err(_) => Err(Status::InternalServerError)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r AuthenticatedUser {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        //? Cached, so several guards in the same request only hit the database once
+        let result: &Result<AuthenticatedUser, Status> = request.local_cache_async(authenticate_user(request)).await;
+
+        match result {
+            Ok(authenticated_user) => Outcome::Success(authenticated_user),
+            Err(status) => Outcome::Error((*status, ()))
+        }
+    }
+}
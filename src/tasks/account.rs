@@ -0,0 +1,22 @@
+use std::time::Duration;
+
+use crate::{db::Database, tasks::mqtt::MqttClient, utils::env_or};
+
+//? Permanently removes accounts whose deletion grace period is over
+pub async fn run_account_purger(db: Database, mqtt_client: MqttClient) {
+    let interval_seconds: u64 = env_or("ACCOUNT_PURGE_INTERVAL_SECONDS", 3600);
+    let mut interval = tokio::time::interval(Duration::from_secs(interval_seconds));
+
+    loop {
+        interval.tick().await;
+
+        match db.purge_deleted_users().await {
+            Ok((0, _)) => (),
+            Ok((purged_count, topic_names)) => {
+                topic_names.iter().for_each(|topic_name| mqtt_client.unsubscribe(topic_name));
+                println!("[Account Purger] Removed {} deleted account(s)", purged_count);
+            },
+            Err(_) => println!("[Account Purger] Failed to remove deleted accounts")
+        };
+    }
+}
@@ -0,0 +1,20 @@
+use std::time::Duration;
+
+use crate::{db::Database, utils::env_or};
+
+//? Flips devices to offline once they've been silent for longer than `DEVICE_OFFLINE_AFTER_SECONDS`
+pub async fn run_offline_detector(db: Database) {
+    let interval_seconds: u64 = env_or("DEVICE_OFFLINE_CHECK_INTERVAL_SECONDS", 30);
+    let silence_window: Duration = Duration::from_secs(env_or("DEVICE_OFFLINE_AFTER_SECONDS", 120));
+    let mut interval = tokio::time::interval(Duration::from_secs(interval_seconds));
+
+    loop {
+        interval.tick().await;
+
+        match db.mark_stale_devices_offline(silence_window).await {
+            Ok(0) => (),
+            Ok(offline_count) => println!("[Offline Detector] Marked {} device(s) offline", offline_count),
+            Err(_) => println!("[Offline Detector] Failed to check for silent devices")
+        };
+    }
+}
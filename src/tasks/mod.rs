@@ -0,0 +1,5 @@
+pub mod registration;
+pub mod account;
+pub mod mqtt;
+pub mod telemetry;
+pub mod device;
\ No newline at end of file
@@ -0,0 +1,141 @@
+use std::time::Duration;
+
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, QoS, SubscribeFilter};
+
+use crate::{db::Database, types::error::ErrorType, utils::env_or};
+
+//? Handle to the backend's own broker connection, `None` when `MQTT_BROKER_URL` isn't set
+#[derive(Clone)]
+pub struct MqttClient {
+    client: Option<AsyncClient>
+}
+
+impl MqttClient {
+    pub fn from_env() -> (Self, Option<EventLoop>) {
+        let broker_url: String = match std::env::var("MQTT_BROKER_URL") {
+            Ok(res) if !res.is_empty() => res,
+            _ => {
+                println!("[MQTT Client] 'MQTT_BROKER_URL' isn't set, controllable state won't be mirrored");
+                return (Self { client: None }, None);
+            }
+        };
+
+        //? rumqttc wants the client id in the url, e.g. `mqtt://localhost:1883?client_id=iotconnect-backend`
+        let broker_url: String = match broker_url.contains("client_id=") {
+            true => broker_url,
+            false => {
+                let client_id: String = env_or("MQTT_CLIENT_ID", String::from("iotconnect-backend"));
+                let separator: &str = if broker_url.contains('?') { "&" } else { "?" };
+                format!("{}{}client_id={}", broker_url, separator, client_id)
+            }
+        };
+
+        let mut mqtt_options: MqttOptions = match MqttOptions::parse_url(broker_url) {
+            Ok(res) => res,
+            Err(err) => {
+                println!("[MQTT Client] 'MQTT_BROKER_URL' is not valid. Error: {}", err);
+                return (Self { client: None }, None);
+            }
+        };
+
+        if let (Ok(superuser), Ok(superuser_pass)) = (std::env::var("MQTT_SUPERUSER"), std::env::var("MQTT_SUPERUSER_PASS")) {
+            mqtt_options.set_credentials(superuser, superuser_pass);
+        }
+        mqtt_options.set_keep_alive(Duration::from_secs(env_or("MQTT_KEEP_ALIVE_SECONDS", 30)));
+
+        let (client, eventloop) = AsyncClient::new(mqtt_options, 100);
+
+        (Self { client: Some(client) }, Some(eventloop))
+    }
+
+    //? Like `publish`, never waits on a full outgoing queue, `subscribe_all` catches up once the broker is back
+    pub fn subscribe(&self, topic_name: &str) {
+        let Some(client) = &self.client else { return };
+
+        if let Err(err) = client.try_subscribe(topic_name, QoS::AtLeastOnce) {
+            println!("[MQTT Client] Failed to subscribe to a topic. Error: {}", err);
+        }
+    }
+
+    pub fn unsubscribe(&self, topic_name: &str) {
+        let Some(client) = &self.client else { return };
+
+        if let Err(err) = client.try_unsubscribe(topic_name) {
+            println!("[MQTT Client] Failed to unsubscribe from a topic. Error: {}", err);
+        }
+    }
+
+    pub async fn publish(&self, topic_name: &str, payload: &str, qos: QoS, retain: bool) -> Result<(), ErrorType> {
+        let Some(client) = &self.client else {
+            return Err(ErrorType::BrokerUnavailable(Some(String::from("'MQTT_BROKER_URL' isn't set"))));
+        };
+
+        //? Don't hold the request up when the broker is gone and the outgoing queue is full
+        match client.try_publish(topic_name, qos, retain, payload.as_bytes().to_vec()) {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                println!("[MQTT Client] Failed to publish to a topic. Error: {}", err);
+                Err(ErrorType::BrokerUnavailable(Some(err.to_string())))
+            }
+        }
+    }
+
+    async fn subscribe_all(&self, db: Database) {
+        let Some(client) = &self.client else { return };
+
+        let topic_names: Vec<String> = match db.list_topic_names().await {
+            Ok(res) => res,
+            Err(_) => {
+                println!("[MQTT Client] Failed to get controllable topics");
+                return;
+            }
+        };
+
+        for topic_chunk in topic_names.chunks(100) {
+            let filters = topic_chunk.iter().map(|topic_name| SubscribeFilter::new(topic_name.clone(), QoS::AtLeastOnce));
+
+            if let Err(err) = client.subscribe_many(filters).await {
+                println!("[MQTT Client] Failed to subscribe to controllable topics. Error: {}", err);
+                return;
+            }
+        }
+
+        println!("[MQTT Client] Subscribed to {} controllable topic(s)", topic_names.len());
+    }
+}
+
+//? Drives the broker connection and mirrors every controllable value into MongoDB
+pub async fn run_mqtt_client(db: Database, mqtt_client: MqttClient, mut eventloop: EventLoop) {
+    let reconnect_seconds: u64 = env_or("MQTT_RECONNECT_SECONDS", 5);
+
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                println!("[MQTT Client] Connected to the broker");
+
+                //? Subscriptions go through the same channel this loop drains, so they can't be awaited here
+                tokio::spawn({
+                    let db = db.clone();
+                    let mqtt_client = mqtt_client.clone();
+                    async move { mqtt_client.subscribe_all(db).await }
+                });
+            },
+            //? Only state topics are subscribed, so this is the device reporting. Retained messages replayed on
+            //? (re)subscribe are old news and would mark an offline device online.
+            Ok(Event::Incoming(Packet::Publish(publish))) if !publish.retain => {
+                let value: String = String::from_utf8_lossy(&publish.payload).into_owned();
+
+                match db.record_controllable_value(&publish.topic, &value).await {
+                    Ok(_) => (),
+                    Err(ErrorType::ControllableNotFound(_)) => (),
+                    Err(_) => println!("[MQTT Client] Failed to store a controllable value")
+                };
+            },
+            Ok(_) => (),
+            Err(err) => {
+                println!("[MQTT Client] Connection error, retrying in {}s. Error: {}", reconnect_seconds, err);
+                tokio::time::sleep(Duration::from_secs(reconnect_seconds)).await;
+            }
+        };
+    }
+}
@@ -0,0 +1,19 @@
+use std::time::Duration;
+
+use crate::{db::Database, utils::env_or};
+
+//? Removes registrations that were never confirmed or never set up before their token expired
+pub async fn run_registration_sweeper(db: Database) {
+    let interval_seconds: u64 = env_or("REGISTRATION_SWEEP_INTERVAL_SECONDS", 3600);
+    let mut interval = tokio::time::interval(Duration::from_secs(interval_seconds));
+
+    loop {
+        interval.tick().await;
+
+        match db.delete_expired_registrations().await {
+            Ok(0) => (),
+            Ok(deleted_count) => println!("[Registration Sweeper] Removed {} abandoned registration(s)", deleted_count),
+            Err(_) => println!("[Registration Sweeper] Failed to remove abandoned registrations")
+        };
+    }
+}
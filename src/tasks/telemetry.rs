@@ -0,0 +1,19 @@
+use std::time::Duration;
+
+use crate::{db::Database, utils::env_or};
+
+//? Rolls raw telemetry up into hourly/daily buckets and expires what the retention policies no longer keep
+pub async fn run_telemetry_rollup(db: Database) {
+    let interval_seconds: u64 = env_or("TELEMETRY_ROLLUP_INTERVAL_SECONDS", 3600);
+    let mut interval = tokio::time::interval(Duration::from_secs(interval_seconds));
+
+    loop {
+        interval.tick().await;
+
+        match db.run_telemetry_rollup().await {
+            Ok(0) => (),
+            Ok(device_count) => println!("[Telemetry Rollup] Rolled up telemetry of {} device(s)", device_count),
+            Err(_) => println!("[Telemetry Rollup] Failed to roll up telemetry")
+        };
+    }
+}
@@ -1,7 +1,8 @@
 use crate::types::db_model::User;
+use mongodb::bson::{oid::ObjectId, DateTime};
 use serde::Serialize;
 
-use super::db_model::{Controllable, Device};
+use super::db_model::{CommandTable, Controllable, Device, DeviceStatus, DeviceStatusChangeTable, HistoryBucket, HistoryReading, RetentionPolicy, UptimeReport};
 
 #[derive(Serialize)]
 #[serde(crate = "rocket::serde")]
@@ -22,18 +23,133 @@ pub enum ResponseBodyType {
         id: String
     },
     UserSetup {
-        user_data: User
+        user_data: UserData,
+        mqtt_pass: String
     },
     UserLogin {
-        user_data: User
+        user_data: UserData
     },
     UserGet {
-        user_data: User
+        user_data: UserData
+    },
+    UserUpdate {
+        user_data: UserData
+    },
+    RotateMqttCredentials {
+        mqtt_user: String,
+        mqtt_pass: String,
+        previous_credentials_expires_at: Option<DateTime>
     },
     CreateDevice {
-        device_data: Device
+        device_data: DeviceData,
+        device_pass: String
+    },
+    RotateDeviceCredentials {
+        device_key: String,
+        device_pass: String,
+        previous_credentials_expires_at: Option<DateTime>
+    },
+    ListDevices {
+        devices: Vec<DeviceData>,
+        page: u64,
+        per_page: u64,
+        total: u64
+    },
+    GetDevice {
+        device_data: DeviceData
+    },
+    UpdateDevice {
+        device_data: DeviceData
     },
     CreateControllable {
         controllable_data: Controllable
+    },
+    ListControllables {
+        controllables: Vec<Controllable>
+    },
+    GetControllable {
+        controllable_data: Controllable
+    },
+    UpdateControllable {
+        controllable_data: Controllable
+    },
+    SendCommand {
+        command_data: CommandTable
+    },
+    ControllableHistory {
+        bucket: String,
+        from: DateTime,
+        to: DateTime,
+        buckets: Vec<HistoryBucket>
+    },
+    ControllableReadings {
+        from: DateTime,
+        to: DateTime,
+        readings: Vec<HistoryReading>,
+        page: u64,
+        per_page: u64,
+        total: u64
+    },
+    DeviceConnectivity {
+        events: Vec<DeviceStatusChangeTable>,
+        page: u64,
+        per_page: u64,
+        total: u64
+    },
+    DeviceUptime {
+        uptime_report: UptimeReport
+    }
+}
+//? What clients get to see of a user, secrets are only handed out once by the endpoints that create them
+#[derive(Serialize)]
+pub struct UserData {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub username: String,
+    pub email: String,
+    pub mqtt_user: String,
+    pub deleted_at: Option<DateTime>,
+    pub retention: Option<RetentionPolicy>
+}
+
+impl From<User> for UserData {
+    fn from(user: User) -> Self {
+        Self {
+            id: user.id,
+            username: user.username,
+            email: user.email,
+            mqtt_user: user.mqtt_user,
+            deleted_at: user.deleted_at,
+            retention: user.retention
+        }
     }
-}
\ No newline at end of file
+}
+
+//? What clients get to see of a device, without its pass or pass hashes
+#[derive(Serialize)]
+pub struct DeviceData {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub device_name: String,
+    pub status: DeviceStatus,
+    pub device_key: String,
+    pub last_online: Option<DateTime>,
+    pub created_at: DateTime,
+    pub owner_id: ObjectId,
+    pub retention: Option<RetentionPolicy>
+}
+
+impl From<Device> for DeviceData {
+    fn from(device: Device) -> Self {
+        Self {
+            id: device.id,
+            device_name: device.device_name,
+            status: device.status,
+            device_key: device.device_key,
+            last_online: device.last_online,
+            created_at: device.created_at,
+            owner_id: device.owner_id,
+            retention: device.retention
+        }
+    }
+}
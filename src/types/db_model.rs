@@ -1,9 +1,11 @@
+use std::{str::FromStr, time::Duration};
+
 use mongodb::bson::{oid::ObjectId, DateTime};
 use serde::{Deserialize, Serialize};
 
-use crate::utils::{generate_long_token, generate_token};
+use crate::utils::{env_or, generate_long_token, generate_token};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
     #[serde(rename = "_id")]
     pub id: ObjectId,
@@ -11,7 +13,23 @@ pub struct User {
     pub email: String,
     pub password: String,
     pub mqtt_user: String,
-    pub mqtt_pass: String
+    pub mqtt_pass: String,
+    //? Credentials replaced by a rotation, the broker keeps accepting them until `expires_at`
+    #[serde(default)]
+    pub previous_mqtt_credentials: Option<PreviousMqttCredentials>,
+    //? Set when the user asked to delete the account, purged once the grace period is over
+    #[serde(default)]
+    pub deleted_at: Option<DateTime>,
+    //? Telemetry retention for all of the user's devices, `None` uses the server defaults
+    #[serde(default)]
+    pub retention: Option<RetentionPolicy>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PreviousMqttCredentials {
+    pub mqtt_user: String,
+    pub mqtt_pass: String,
+    pub expires_at: DateTime
 }
 
 impl User {
@@ -22,7 +40,10 @@ impl User {
             password,
             id: ObjectId::new(),
             mqtt_pass: generate_long_token(),
-            mqtt_user: generate_long_token()
+            mqtt_user: generate_long_token(),
+            previous_mqtt_credentials: None,
+            deleted_at: None,
+            retention: None
         }
     }
 }
@@ -36,7 +57,10 @@ pub struct RegistrationTable {
     pub setup_token: String,
     pub email: String,
     pub created_at: DateTime,
-    pub confirmed: bool
+    pub confirmed: bool,
+    //? Covers the confirmation token until confirmed, then the setup token
+    #[serde(default = "already_expired")]
+    pub expires_at: DateTime
 }
 
 impl RegistrationTable {
@@ -47,9 +71,25 @@ impl RegistrationTable {
             confirmation_token: generate_token(),
             confirmed: false,
             created_at: DateTime::now(),
-            setup_token: generate_token()
+            setup_token: generate_token(),
+            expires_at: RegistrationTable::confirmation_expiry()
         }
     }
+
+    pub fn confirmation_expiry() -> DateTime {
+        let ttl_seconds: u64 = env_or("REGISTRATION_CONFIRMATION_TTL_SECONDS", 86400);
+        DateTime::now().saturating_add_duration(Duration::from_secs(ttl_seconds))
+    }
+
+    pub fn setup_expiry() -> DateTime {
+        let ttl_seconds: u64 = env_or("REGISTRATION_SETUP_TTL_SECONDS", 3600);
+        DateTime::now().saturating_add_duration(Duration::from_secs(ttl_seconds))
+    }
+}
+
+//? Rows written before expiry existed are treated as already expired
+fn already_expired() -> DateTime {
+    DateTime::from_millis(0)
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -59,15 +99,116 @@ pub struct LoginOTPTable {
     pub confirmation_token: String,
     pub email: String,
     pub created_at: DateTime,
+    pub expires_at: DateTime
 }
 
 impl LoginOTPTable {
     pub fn new(email: String) -> Self {
+        let created_at: DateTime = DateTime::now();
+        let ttl_seconds: u64 = env_or("OTP_TTL_SECONDS", 300);
+
         Self {
             email,
             id: ObjectId::new(),
             confirmation_token: generate_token(),
-            created_at: DateTime::now()
+            created_at,
+            expires_at: created_at.saturating_add_duration(Duration::from_secs(ttl_seconds))
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LoginOTPAttempt {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub email: String,
+    pub failed_attempts: i32,
+    pub locked_until: Option<DateTime>,
+    pub expires_at: DateTime
+}
+
+
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PasswordResetTable {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub user_id: ObjectId,
+    pub token_hash: String,
+    pub created_at: DateTime,
+    pub expires_at: DateTime
+}
+
+impl PasswordResetTable {
+    pub fn new(user_id: ObjectId, token_hash: String) -> Self {
+        let created_at: DateTime = DateTime::now();
+        let ttl_seconds: u64 = env_or("PASSWORD_RESET_TTL_SECONDS", 3600);
+
+        Self {
+            user_id,
+            token_hash,
+            id: ObjectId::new(),
+            created_at,
+            expires_at: created_at.saturating_add_duration(Duration::from_secs(ttl_seconds))
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EmailChangeTable {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub user_id: ObjectId,
+    pub new_email: String,
+    pub token_hash: String,
+    pub created_at: DateTime,
+    pub expires_at: DateTime
+}
+
+impl EmailChangeTable {
+    pub fn new(user_id: ObjectId, new_email: String, token_hash: String) -> Self {
+        let created_at: DateTime = DateTime::now();
+        let ttl_seconds: u64 = env_or("EMAIL_CHANGE_TTL_SECONDS", 3600);
+
+        Self {
+            user_id,
+            new_email,
+            token_hash,
+            id: ObjectId::new(),
+            created_at,
+            expires_at: created_at.saturating_add_duration(Duration::from_secs(ttl_seconds))
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionTable {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    //? Every refresh token rotated out of the same login shares the family
+    pub family_id: ObjectId,
+    pub user_id: ObjectId,
+    pub refresh_token_hash: String,
+    pub created_at: DateTime,
+    pub expires_at: DateTime,
+    pub used: bool,
+    pub revoked: bool
+}
+
+impl SessionTable {
+    pub fn new(family_id: ObjectId, user_id: ObjectId, refresh_token_hash: String) -> Self {
+        let created_at: DateTime = DateTime::now();
+        let ttl_days: u64 = env_or("REFRESH_TOKEN_TTL_DAYS", 30);
+
+        Self {
+            family_id,
+            user_id,
+            refresh_token_hash,
+            id: ObjectId::new(),
+            created_at,
+            expires_at: created_at.saturating_add_duration(Duration::from_secs(ttl_days * 86400)),
+            used: false,
+            revoked: false
         }
     }
 }
@@ -79,29 +220,97 @@ pub struct Device {
     #[serde(rename = "_id")]
     pub id: ObjectId,
     pub device_name: String,
-    pub status: i32,
+    pub status: DeviceStatus,
     pub device_key: String,
-    pub device_pass: String,
+    //? Plaintext secret of devices created before hashing, moved to `device_pass_hash` on their next authentication
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_pass: Option<String>,
+    #[serde(default)]
+    pub device_pass_hash: String,
+    #[serde(default)]
+    pub previous_credentials: Option<PreviousDeviceCredentials>,
     pub last_online: Option<DateTime>,
     pub created_at: DateTime,
-    pub user_email: String
+    pub owner_id: ObjectId,
+    //? Overrides the owner's retention for this device only
+    #[serde(default)]
+    pub retention: Option<RetentionPolicy>
 }
 
 impl Device {
-    pub fn new(device_name: String, user_email: String) -> Self {
+    pub fn new(device_name: String, owner_id: ObjectId, device_pass_hash: String) -> Self {
         Self {
             device_name,
-            user_email,
+            owner_id,
+            device_pass_hash,
             device_key: generate_long_token(),
-            device_pass: generate_long_token(),
+            device_pass: None,
+            previous_credentials: None,
             id: ObjectId::new(),
-            status: 0,
+            status: DeviceStatus::Offline,
             created_at: DateTime::now(),
             last_online: None,
+            retention: None
+        }
+    }
+}
+
+//? Stored as its number so devices written before the enum, which all hold `0`, read back as offline
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(into = "i32", try_from = "i32")]
+pub enum DeviceStatus {
+    Offline,
+    Online
+}
+
+impl From<DeviceStatus> for i32 {
+    fn from(status: DeviceStatus) -> Self {
+        match status {
+            DeviceStatus::Offline => 0,
+            DeviceStatus::Online => 1
+        }
+    }
+}
+
+impl TryFrom<i32> for DeviceStatus {
+    type Error = String;
+
+    fn try_from(status: i32) -> Result<Self, Self::Error> {
+        match status {
+            0 => Ok(Self::Offline),
+            1 => Ok(Self::Online),
+            _ => Err(format!("Unknown device status {}", status))
         }
     }
 }
 
+//? Accepts the names as well as the stored numbers
+impl FromStr for DeviceStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "offline" | "0" => Ok(Self::Offline),
+            "online" | "1" => Ok(Self::Online),
+            _ => Err(format!("Unknown device status {}", s))
+        }
+    }
+}
+
+//? Credentials replaced by a rotation, still accepted until `expires_at` so the device can re-provision itself
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PreviousDeviceCredentials {
+    pub device_key: String,
+    pub device_pass_hash: String,
+    pub expires_at: DateTime
+}
+
+
+fn output_direction() -> ControllableDirection {
+    ControllableDirection::Output
+}
+
+pub const COMMAND_TOPIC_SUFFIX: &str = "/set";
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Controllable {
@@ -111,40 +320,798 @@ pub struct Controllable {
     pub device_id: ObjectId,
     pub created_at: DateTime,
     pub category: ControllableCategory,
+    //? Controllables stored before directions existed were all actuators
+    #[serde(default = "output_direction")]
+    pub direction: ControllableDirection,
     pub topic_name: String,
-    pub user_email: String
+    pub owner_id: ObjectId,
+    //? Missing on controllables created before configs existed, `config()` falls back to the category default
+    #[serde(default)]
+    pub config: Option<ControllableConfig>,
+    //? Kept next to the config like `direction` is next to the category, so responses carry it without a lookup
+    #[serde(default)]
+    pub unit: Option<String>,
+    #[serde(default)]
+    pub last_value: Option<String>,
+    #[serde(default)]
+    pub last_value_at: Option<DateTime>
 }
 
 impl Controllable {
-    pub fn new(controllable_name: String, controllable_category: ControllableCategory, device_id: ObjectId, user_email: String) -> Self {
+    pub fn new(controllable_name: String, controllable_category: ControllableCategory, controllable_config: ControllableConfig, device_id: ObjectId, owner_id: ObjectId) -> Self {
         Self {
             controllable_name,
             device_id,
-            user_email,
+            owner_id,
             topic_name: generate_long_token(),
             id: ObjectId::new(),
             created_at: DateTime::now(),
             category: controllable_category,
+            direction: controllable_category.direction(),
+            unit: controllable_config.unit(),
+            config: Some(controllable_config),
+            last_value: None,
+            last_value_at: None
+        }
+    }
+
+    //? Commands go to their own topic so the backend and users never publish on the topic devices report state on
+    pub fn command_topic(&self) -> String {
+        format!("{}{}", self.topic_name, COMMAND_TOPIC_SUFFIX)
+    }
+
+    pub fn config(&self) -> ControllableConfig {
+        match &self.config {
+            Some(config) => config.clone(),
+            None => ControllableConfig::default_for(&self.category)
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 pub enum ControllableCategory {
     Button,
     Slider,
     Switch,
-    LED
+    LED,
+    RGBLight,
+    Thermostat,
+    Servo,
+    TimedRelay,
+    Temperature,
+    Humidity,
+    Motion,
+    DoorContact
+}
+
+//? Input controllables only report values, output controllables also take commands
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ControllableDirection {
+    Input,
+    Output
+}
+
+//? Case and separators don't matter, so `rgb_light`, `RGB Light` and `RGBLight` are the same category
+impl FromStr for ControllableCategory {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let normalized: String = s.chars().filter(|c| !matches!(c, ' ' | '_' | '-')).collect::<String>().to_lowercase();
+
+        match normalized.as_str() {
+            "button" => Ok(Self::Button),
+            "slider" => Ok(Self::Slider),
+            "switch" => Ok(Self::Switch),
+            "led" => Ok(Self::LED),
+            "rgblight" => Ok(Self::RGBLight),
+            "thermostat" => Ok(Self::Thermostat),
+            "servo" => Ok(Self::Servo),
+            "timedrelay" => Ok(Self::TimedRelay),
+            "temperature" => Ok(Self::Temperature),
+            "humidity" => Ok(Self::Humidity),
+            "motion" => Ok(Self::Motion),
+            "doorcontact" => Ok(Self::DoorContact),
+            _ => Err(format!("Unknown controllable category {}", s))
+        }
+    }
 }
 
 impl ControllableCategory {
-    pub fn from_str(s: &str) -> Option<Self> {
-        match s {
-            "Button" => Some(Self::Button),
-            "Slider" => Some(Self::Slider),
-            "Switch" => Some(Self::Switch),
-            "LED" => Some(Self::LED),
+    pub fn direction(&self) -> ControllableDirection {
+        match self {
+            Self::Temperature | Self::Humidity | Self::Motion | Self::DoorContact => ControllableDirection::Input,
+            _ => ControllableDirection::Output
+        }
+    }
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum LedColorModel {
+    Mono,
+    RGB
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum TemperatureUnit {
+    Celsius,
+    Fahrenheit,
+    Kelvin
+}
+
+impl TemperatureUnit {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Self::Celsius => "°C",
+            Self::Fahrenheit => "°F",
+            Self::Kelvin => "K"
+        }
+    }
+}
+
+//? Per-category settings, tagged with the category so devices can read it on its own
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "category")]
+pub enum ControllableConfig {
+    Button {
+        press_payload: String,
+        //? Sent for a `false` command, momentary buttons use it to signal the release
+        release_payload: Option<String>
+    },
+    Slider {
+        min: f64,
+        max: f64,
+        step: f64,
+        unit: Option<String>
+    },
+    Switch {
+        on_payload: String,
+        off_payload: String
+    },
+    LED {
+        color_model: LedColorModel,
+        max_brightness: u32
+    },
+    RGBLight {
+        max_brightness: u32
+    },
+    Thermostat {
+        unit: TemperatureUnit,
+        min_setpoint: f64,
+        max_setpoint: f64,
+        step: f64
+    },
+    Servo {
+        min_angle: f64,
+        max_angle: f64
+    },
+    TimedRelay {
+        on_payload: String,
+        off_payload: String,
+        max_duration_seconds: u64
+    },
+    Temperature {
+        unit: TemperatureUnit,
+        report_interval_seconds: u32
+    },
+    Humidity {
+        report_interval_seconds: u32
+    },
+    Motion {
+        //? How long the firmware waits before reporting motion again
+        cooldown_seconds: u32
+    },
+    DoorContact {
+        //? For reed switches wired normally-closed
+        inverted: bool
+    }
+}
+
+impl ControllableConfig {
+    pub fn default_for(category: &ControllableCategory) -> Self {
+        match category {
+            ControllableCategory::Button => Self::Button { press_payload: String::from("1"), release_payload: None },
+            ControllableCategory::Slider => Self::Slider { min: 0.0, max: 100.0, step: 1.0, unit: None },
+            ControllableCategory::Switch => Self::Switch { on_payload: String::from("1"), off_payload: String::from("0") },
+            ControllableCategory::LED => Self::LED { color_model: LedColorModel::Mono, max_brightness: 255 },
+            ControllableCategory::RGBLight => Self::RGBLight { max_brightness: 255 },
+            ControllableCategory::Thermostat => Self::Thermostat { unit: TemperatureUnit::Celsius, min_setpoint: 5.0, max_setpoint: 30.0, step: 0.5 },
+            ControllableCategory::Servo => Self::Servo { min_angle: 0.0, max_angle: 180.0 },
+            ControllableCategory::TimedRelay => Self::TimedRelay { on_payload: String::from("1"), off_payload: String::from("0"), max_duration_seconds: 3600 },
+            ControllableCategory::Temperature => Self::Temperature { unit: TemperatureUnit::Celsius, report_interval_seconds: 60 },
+            ControllableCategory::Humidity => Self::Humidity { report_interval_seconds: 60 },
+            ControllableCategory::Motion => Self::Motion { cooldown_seconds: 30 },
+            ControllableCategory::DoorContact => Self::DoorContact { inverted: false }
+        }
+    }
+
+    pub fn category(&self) -> ControllableCategory {
+        match self {
+            Self::Button { .. } => ControllableCategory::Button,
+            Self::Slider { .. } => ControllableCategory::Slider,
+            Self::Switch { .. } => ControllableCategory::Switch,
+            Self::LED { .. } => ControllableCategory::LED,
+            Self::RGBLight { .. } => ControllableCategory::RGBLight,
+            Self::Thermostat { .. } => ControllableCategory::Thermostat,
+            Self::Servo { .. } => ControllableCategory::Servo,
+            Self::TimedRelay { .. } => ControllableCategory::TimedRelay,
+            Self::Temperature { .. } => ControllableCategory::Temperature,
+            Self::Humidity { .. } => ControllableCategory::Humidity,
+            Self::Motion { .. } => ControllableCategory::Motion,
+            Self::DoorContact { .. } => ControllableCategory::DoorContact
+        }
+    }
+
+    //? Unit of the values the controllable reports or takes, `None` for unitless ones
+    pub fn unit(&self) -> Option<String> {
+        match self {
+            Self::Slider { unit, .. } => unit.clone(),
+            Self::Thermostat { unit, .. } | Self::Temperature { unit, .. } => Some(String::from(unit.symbol())),
+            Self::Servo { .. } => Some(String::from("°")),
+            Self::Humidity { .. } => Some(String::from("%")),
+            _ => None
+        }
+    }
+
+    //? Returns why the config can't be used, payloads end up on the wire as-is so they must stay short
+    pub fn validate(&self) -> Result<(), String> {
+        let is_valid_payload = |payload: &str| !payload.is_empty() && payload.len() <= 64;
+
+        match self {
+            Self::Button { press_payload, release_payload } => {
+                if !is_valid_payload(press_payload) {
+                    return Err(String::from("Button press payload must be 1 to 64 characters."));
+                }
+                match release_payload {
+                    Some(release_payload) if !is_valid_payload(release_payload) || release_payload == press_payload => Err(String::from("Button release payload must be 1 to 64 characters and differ from the press payload.")),
+                    _ => Ok(())
+                }
+            },
+            Self::Slider { min, max, step, unit } => {
+                if !min.is_finite() || !max.is_finite() || min >= max {
+                    return Err(String::from("Slider min must be lower than max."));
+                }
+                if !step.is_finite() || *step <= 0.0 || *step > max - min {
+                    return Err(String::from("Slider step must be positive and fit between min and max."));
+                }
+                match unit {
+                    Some(unit) if unit.len() > 16 => Err(String::from("Slider unit must be at most 16 characters.")),
+                    _ => Ok(())
+                }
+            },
+            Self::Switch { on_payload, off_payload } | Self::TimedRelay { on_payload, off_payload, .. } => {
+                if !is_valid_payload(on_payload) || !is_valid_payload(off_payload) || on_payload == off_payload {
+                    return Err(String::from("On and off payloads must be 1 to 64 characters and differ from each other."));
+                }
+                match self {
+                    Self::TimedRelay { max_duration_seconds: 0, .. } => Err(String::from("Relay max duration must be positive.")),
+                    _ => Ok(())
+                }
+            },
+            Self::LED { max_brightness, .. } | Self::RGBLight { max_brightness } => {
+                if *max_brightness == 0 {
+                    return Err(String::from("Max brightness must be positive."));
+                }
+                Ok(())
+            },
+            Self::Thermostat { min_setpoint, max_setpoint, step, .. } => {
+                if !min_setpoint.is_finite() || !max_setpoint.is_finite() || min_setpoint >= max_setpoint {
+                    return Err(String::from("Thermostat min setpoint must be lower than max setpoint."));
+                }
+                if !step.is_finite() || *step <= 0.0 || *step > max_setpoint - min_setpoint {
+                    return Err(String::from("Thermostat step must be positive and fit between the setpoints."));
+                }
+                Ok(())
+            },
+            Self::Servo { min_angle, max_angle } => {
+                if !min_angle.is_finite() || !max_angle.is_finite() || *min_angle < 0.0 || *max_angle > 360.0 || min_angle >= max_angle {
+                    return Err(String::from("Servo angles must be within 0 to 360 and min must be lower than max."));
+                }
+                Ok(())
+            },
+            Self::Temperature { report_interval_seconds, .. } | Self::Humidity { report_interval_seconds } => {
+                if *report_interval_seconds == 0 {
+                    return Err(String::from("Report interval must be positive."));
+                }
+                Ok(())
+            },
+            Self::Motion { .. } | Self::DoorContact { .. } => Ok(())
+        }
+    }
+
+    //? Turns a command value into the payload devices expect on their command topic, `None` if the config can't take it
+    pub fn command_payload(&self, value: &serde_json::Value) -> Option<String> {
+        //? Numbers within a range, landing on a step if there's one, with some room for float noise
+        let stepped_number = |value: &serde_json::Value, min: f64, max: f64, step: Option<f64>| -> Option<String> {
+            let number: f64 = value.as_f64()?;
+            if !number.is_finite() || number < min || number > max {
+                return None;
+            }
+            if let Some(step) = step {
+                let steps: f64 = (number - min) / step;
+                if (steps - steps.round()).abs() > 1e-6 {
+                    return None;
+                }
+            }
+
+            Some(number.to_string())
+        };
+        let rgb_payload = |color: &serde_json::Map<String, serde_json::Value>, max_brightness: u32| -> Option<String> {
+            let channel = |name: &str| color.get(name).and_then(|channel| channel.as_u64()).filter(|channel| *channel <= 255);
+            let brightness: u64 = match color.get("brightness") {
+                Some(brightness) => brightness.as_u64().filter(|brightness| *brightness <= max_brightness as u64)?,
+                None => max_brightness as u64
+            };
+
+            Some(format!("{},{},{},{}", channel("r")?, channel("g")?, channel("b")?, brightness))
+        };
+
+        match (self, value) {
+            (Self::Button { press_payload, .. }, serde_json::Value::Null) | (Self::Button { press_payload, .. }, serde_json::Value::Bool(true)) => Some(press_payload.clone()),
+            (Self::Button { release_payload, .. }, serde_json::Value::Bool(false)) => release_payload.clone(),
+            (Self::Switch { on_payload, off_payload }, serde_json::Value::Bool(state)) | (Self::TimedRelay { on_payload, off_payload, .. }, serde_json::Value::Bool(state)) => Some(if *state { on_payload.clone() } else { off_payload.clone() }),
+            (Self::Switch { on_payload, off_payload }, serde_json::Value::Number(state)) => match state.as_u64() {
+                Some(0) => Some(off_payload.clone()),
+                Some(1) => Some(on_payload.clone()),
+                _ => None
+            },
+            //? `{"state": true, "duration_seconds": 60}` switches the relay on and lets the firmware switch it back off
+            (Self::TimedRelay { on_payload, off_payload, max_duration_seconds }, serde_json::Value::Object(command)) => {
+                let state: bool = command.get("state")?.as_bool()?;
+                match (state, command.get("duration_seconds")) {
+                    (true, Some(duration)) => duration.as_u64().filter(|duration| *duration > 0 && duration <= max_duration_seconds).map(|duration| format!("{},{}", on_payload, duration)),
+                    (true, None) => Some(on_payload.clone()),
+                    (false, _) => Some(off_payload.clone())
+                }
+            },
+            (Self::Slider { min, max, step, .. }, serde_json::Value::Number(_)) => stepped_number(value, *min, *max, Some(*step)),
+            (Self::Thermostat { min_setpoint, max_setpoint, step, .. }, serde_json::Value::Number(_)) => stepped_number(value, *min_setpoint, *max_setpoint, Some(*step)),
+            (Self::Servo { min_angle, max_angle }, serde_json::Value::Number(_)) => stepped_number(value, *min_angle, *max_angle, None),
+            (Self::LED { color_model: LedColorModel::Mono, max_brightness }, serde_json::Value::Bool(state)) => Some(if *state { max_brightness.to_string() } else { String::from("0") }),
+            (Self::LED { color_model: LedColorModel::Mono, max_brightness }, serde_json::Value::Number(brightness)) => brightness.as_u64().filter(|brightness| *brightness <= *max_brightness as u64).map(|brightness| brightness.to_string()),
+            (Self::LED { color_model: LedColorModel::RGB, max_brightness }, serde_json::Value::Object(color)) | (Self::RGBLight { max_brightness }, serde_json::Value::Object(color)) => rgb_payload(color, *max_brightness),
+            (Self::RGBLight { .. }, serde_json::Value::Bool(false)) => Some(String::from("0,0,0,0")),
             _ => None
         }
     }
-}
\ No newline at end of file
+}
+
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CommandTable {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub controllable_id: ObjectId,
+    pub device_id: ObjectId,
+    pub user_id: ObjectId,
+    pub topic_name: String,
+    pub payload: String,
+    pub qos: i32,
+    pub retain: bool,
+    pub created_at: DateTime
+}
+
+impl CommandTable {
+    pub fn new(controllable: &Controllable, user_id: ObjectId, payload: String, qos: i32, retain: bool) -> Self {
+        Self {
+            user_id,
+            payload,
+            qos,
+            retain,
+            id: ObjectId::new(),
+            controllable_id: controllable.id,
+            device_id: controllable.device_id,
+            topic_name: controllable.command_topic(),
+            created_at: DateTime::now()
+        }
+    }
+}
+
+
+//? Readings live in a time-series collection, `meta` is what the series are bucketed by
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TelemetryTable {
+    pub timestamp: DateTime,
+    pub meta: TelemetryMeta,
+    pub value: f64
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TelemetryMeta {
+    pub controllable_id: ObjectId,
+    pub device_id: ObjectId,
+    pub owner_id: ObjectId
+}
+
+impl TelemetryTable {
+    pub fn new(controllable: &Controllable, value: f64, timestamp: DateTime) -> Self {
+        Self {
+            value,
+            timestamp,
+            meta: TelemetryMeta {
+                controllable_id: controllable.id,
+                device_id: controllable.device_id,
+                owner_id: controllable.owner_id
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HistoryBucketSize {
+    Minute,
+    Hour,
+    Day
+}
+
+impl FromStr for HistoryBucketSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1m" => Ok(Self::Minute),
+            "1h" => Ok(Self::Hour),
+            "1d" => Ok(Self::Day),
+            _ => Err(format!("Unknown bucket size {}", s))
+        }
+    }
+}
+
+impl HistoryBucketSize {
+    //? Unit name understood by `$dateTrunc`
+    pub fn unit(&self) -> &'static str {
+        match self {
+            Self::Minute => "minute",
+            Self::Hour => "hour",
+            Self::Day => "day"
+        }
+    }
+
+    pub fn millis(&self) -> i64 {
+        match self {
+            Self::Minute => 60_000,
+            Self::Hour => 3_600_000,
+            Self::Day => 86_400_000
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistoryBucket {
+    pub timestamp: DateTime,
+    pub min: f64,
+    pub max: f64,
+    pub avg: f64,
+    pub last: f64,
+    pub count: i64
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistoryReading {
+    pub timestamp: DateTime,
+    pub value: f64
+}
+
+
+//? How many days telemetry is kept at each resolution
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    pub raw_days: u32,
+    pub hourly_days: u32,
+    pub daily_days: u32
+}
+
+impl RetentionPolicy {
+    pub fn default_policy() -> Self {
+        Self {
+            raw_days: env_or("TELEMETRY_RAW_RETENTION_DAYS", 7),
+            hourly_days: env_or("TELEMETRY_HOURLY_RETENTION_DAYS", 90),
+            daily_days: env_or("TELEMETRY_DAILY_RETENTION_DAYS", 730)
+        }
+    }
+
+    //? Raw readings are also capped collection-wide by Mongo, a policy can't keep them any longer
+    pub fn max_raw_days() -> u32 {
+        env_or("TELEMETRY_RAW_MAX_RETENTION_DAYS", 30)
+    }
+
+    //? How far back every rollup run recomputes buckets, so late readings still make it in
+    pub fn rollup_lookback_hours() -> u32 {
+        env_or("TELEMETRY_ROLLUP_LOOKBACK_HOURS", 48)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.raw_days == 0 || self.raw_days > Self::max_raw_days() {
+            return Err(format!("Raw retention must be 1 to {} days.", Self::max_raw_days()));
+        }
+        if self.hourly_days < self.raw_days || self.hourly_days > 3650 {
+            return Err(String::from("Hourly retention must be at least the raw retention and at most 3650 days."));
+        }
+        //? Daily buckets are recomputed from hourly ones over the lookback, those hours must all still be there
+        let min_hourly_days: u32 = (Self::rollup_lookback_hours() + 24) / 24 + 1;
+        if self.hourly_days < min_hourly_days {
+            return Err(format!("Hourly retention must be at least {} days to cover the rollup lookback.", min_hourly_days));
+        }
+        if self.daily_days < self.hourly_days || self.daily_days > 3650 {
+            return Err(String::from("Daily retention must be at least the hourly retention and at most 3650 days."));
+        }
+        Ok(())
+    }
+}
+
+//? Compacted telemetry, one document per controllable and bucket, removed by Mongo once `expires_at` has passed
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TelemetryRollupTable {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub controllable_id: ObjectId,
+    pub device_id: ObjectId,
+    pub owner_id: ObjectId,
+    pub bucket_start: DateTime,
+    pub min: f64,
+    pub max: f64,
+    pub avg: f64,
+    pub sum: f64,
+    pub count: i64,
+    pub last: f64,
+    pub last_at: DateTime,
+    pub expires_at: DateTime
+}
+
+
+//? One row per online/offline flip of a device
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeviceStatusChangeTable {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub device_id: ObjectId,
+    pub owner_id: ObjectId,
+    pub from_status: DeviceStatus,
+    pub to_status: DeviceStatus,
+    //? What triggered it, e.g. `heartbeat`, `telemetry`, `mqtt` or `timeout`
+    pub reason: String,
+    //? When the flip actually happened, for a timeout that's the last sign of life rather than the detection
+    pub changed_at: DateTime
+}
+
+impl DeviceStatusChangeTable {
+    pub fn new(device: &Device, from_status: DeviceStatus, to_status: DeviceStatus, reason: &str, changed_at: DateTime) -> Self {
+        Self {
+            from_status,
+            to_status,
+            changed_at,
+            id: ObjectId::new(),
+            device_id: device.id,
+            owner_id: device.owner_id,
+            reason: reason.to_string()
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UptimeReport {
+    pub from: DateTime,
+    pub to: DateTime,
+    pub uptime_percent: f64,
+    pub online_seconds: i64,
+    //? Every time the device came back after going offline, reboots while online don't count
+    pub reconnects: u64,
+    pub longest_outage_seconds: i64
+}
+
+impl UptimeReport {
+    pub fn empty(from: DateTime, to: DateTime) -> Self {
+        Self {
+            from,
+            to,
+            uptime_percent: 0.0,
+            online_seconds: 0,
+            reconnects: 0,
+            longest_outage_seconds: 0
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn switch(on_payload: &str, off_payload: &str) -> ControllableConfig {
+        ControllableConfig::Switch { on_payload: String::from(on_payload), off_payload: String::from(off_payload) }
+    }
+
+    fn slider(min: f64, max: f64, step: f64) -> ControllableConfig {
+        ControllableConfig::Slider { min, max, step, unit: None }
+    }
+
+    #[test]
+    fn default_configs_are_valid() {
+        for category in [ControllableCategory::Button, ControllableCategory::Slider, ControllableCategory::Switch, ControllableCategory::LED, ControllableCategory::RGBLight, ControllableCategory::Thermostat, ControllableCategory::Servo, ControllableCategory::TimedRelay] {
+            let config: ControllableConfig = ControllableConfig::default_for(&category);
+
+            assert_eq!(config.validate(), Ok(()));
+            assert_eq!(config.category(), category);
+        }
+    }
+
+    #[test]
+    fn validates_payloads() {
+        assert!(ControllableConfig::Button { press_payload: String::new(), release_payload: None }.validate().is_err());
+        assert!(ControllableConfig::Button { press_payload: "x".repeat(65), release_payload: None }.validate().is_err());
+        assert!(ControllableConfig::Button { press_payload: "x".repeat(64), release_payload: None }.validate().is_ok());
+        assert!(ControllableConfig::Button { press_payload: String::from("1"), release_payload: Some(String::from("1")) }.validate().is_err());
+        assert!(ControllableConfig::Button { press_payload: String::from("1"), release_payload: Some(String::from("0")) }.validate().is_ok());
+
+        assert!(switch("on", "on").validate().is_err());
+        assert!(switch("on", "").validate().is_err());
+        assert!(switch("on", "off").validate().is_ok());
+
+        assert!(ControllableConfig::TimedRelay { on_payload: String::from("1"), off_payload: String::from("0"), max_duration_seconds: 0 }.validate().is_err());
+    }
+
+    #[test]
+    fn validates_ranges() {
+        assert!(slider(10.0, 10.0, 1.0).validate().is_err());
+        assert!(slider(10.0, 0.0, 1.0).validate().is_err());
+        assert!(slider(0.0, f64::INFINITY, 1.0).validate().is_err());
+        assert!(slider(0.0, 10.0, 0.0).validate().is_err());
+        assert!(slider(0.0, 10.0, 11.0).validate().is_err());
+        assert!(slider(0.0, 10.0, 10.0).validate().is_ok());
+        assert!(ControllableConfig::Slider { min: 0.0, max: 10.0, step: 1.0, unit: Some("x".repeat(17)) }.validate().is_err());
+
+        assert!(ControllableConfig::Thermostat { unit: TemperatureUnit::Celsius, min_setpoint: 30.0, max_setpoint: 5.0, step: 0.5 }.validate().is_err());
+        assert!(ControllableConfig::Thermostat { unit: TemperatureUnit::Celsius, min_setpoint: 5.0, max_setpoint: 30.0, step: f64::NAN }.validate().is_err());
+
+        assert!(ControllableConfig::Servo { min_angle: -1.0, max_angle: 180.0 }.validate().is_err());
+        assert!(ControllableConfig::Servo { min_angle: 0.0, max_angle: 361.0 }.validate().is_err());
+        assert!(ControllableConfig::Servo { min_angle: 0.0, max_angle: 360.0 }.validate().is_ok());
+
+        assert!(ControllableConfig::LED { color_model: LedColorModel::RGB, max_brightness: 0 }.validate().is_err());
+        assert!(ControllableConfig::RGBLight { max_brightness: 0 }.validate().is_err());
+    }
+
+    #[test]
+    fn builds_button_and_switch_payloads() {
+        let button = ControllableConfig::Button { press_payload: String::from("press"), release_payload: None };
+        assert_eq!(button.command_payload(&json!(null)), Some(String::from("press")));
+        assert_eq!(button.command_payload(&json!(true)), Some(String::from("press")));
+        assert_eq!(button.command_payload(&json!(false)), None);
+        assert_eq!(button.command_payload(&json!(1)), None);
+
+        let momentary_button = ControllableConfig::Button { press_payload: String::from("press"), release_payload: Some(String::from("release")) };
+        assert_eq!(momentary_button.command_payload(&json!(false)), Some(String::from("release")));
+
+        let switch = switch("on", "off");
+        assert_eq!(switch.command_payload(&json!(true)), Some(String::from("on")));
+        assert_eq!(switch.command_payload(&json!(false)), Some(String::from("off")));
+        assert_eq!(switch.command_payload(&json!(1)), Some(String::from("on")));
+        assert_eq!(switch.command_payload(&json!(0)), Some(String::from("off")));
+        assert_eq!(switch.command_payload(&json!(2)), None);
+        assert_eq!(switch.command_payload(&json!("on")), None);
+    }
+
+    #[test]
+    fn builds_timed_relay_payloads() {
+        let relay = ControllableConfig::TimedRelay { on_payload: String::from("1"), off_payload: String::from("0"), max_duration_seconds: 600 };
+
+        assert_eq!(relay.command_payload(&json!(true)), Some(String::from("1")));
+        assert_eq!(relay.command_payload(&json!({ "state": true })), Some(String::from("1")));
+        assert_eq!(relay.command_payload(&json!({ "state": true, "duration_seconds": 60 })), Some(String::from("1,60")));
+        assert_eq!(relay.command_payload(&json!({ "state": true, "duration_seconds": 600 })), Some(String::from("1,600")));
+        assert_eq!(relay.command_payload(&json!({ "state": true, "duration_seconds": 601 })), None);
+        assert_eq!(relay.command_payload(&json!({ "state": true, "duration_seconds": 0 })), None);
+        assert_eq!(relay.command_payload(&json!({ "state": false, "duration_seconds": 60 })), Some(String::from("0")));
+        assert_eq!(relay.command_payload(&json!({ "duration_seconds": 60 })), None);
+    }
+
+    #[test]
+    fn builds_stepped_number_payloads() {
+        let slider = slider(0.0, 10.0, 0.5);
+        assert_eq!(slider.command_payload(&json!(0)), Some(String::from("0")));
+        assert_eq!(slider.command_payload(&json!(2.5)), Some(String::from("2.5")));
+        assert_eq!(slider.command_payload(&json!(10)), Some(String::from("10")));
+        assert_eq!(slider.command_payload(&json!(2.7)), None);
+        assert_eq!(slider.command_payload(&json!(-0.5)), None);
+        assert_eq!(slider.command_payload(&json!(10.5)), None);
+        assert_eq!(slider.command_payload(&json!(true)), None);
+
+        //? Steps that aren't exact in binary still land
+        let fine_slider = self::slider(0.0, 1.0, 0.1);
+        assert_eq!(fine_slider.command_payload(&json!(0.3)), Some(String::from("0.3")));
+
+        let thermostat = ControllableConfig::Thermostat { unit: TemperatureUnit::Celsius, min_setpoint: 5.0, max_setpoint: 30.0, step: 0.5 };
+        assert_eq!(thermostat.command_payload(&json!(21.5)), Some(String::from("21.5")));
+        assert_eq!(thermostat.command_payload(&json!(21.25)), None);
+
+        let servo = ControllableConfig::Servo { min_angle: 0.0, max_angle: 180.0 };
+        assert_eq!(servo.command_payload(&json!(42.25)), Some(String::from("42.25")));
+        assert_eq!(servo.command_payload(&json!(181)), None);
+    }
+
+    #[test]
+    fn builds_light_payloads() {
+        let mono_led = ControllableConfig::LED { color_model: LedColorModel::Mono, max_brightness: 100 };
+        assert_eq!(mono_led.command_payload(&json!(true)), Some(String::from("100")));
+        assert_eq!(mono_led.command_payload(&json!(false)), Some(String::from("0")));
+        assert_eq!(mono_led.command_payload(&json!(40)), Some(String::from("40")));
+        assert_eq!(mono_led.command_payload(&json!(101)), None);
+        assert_eq!(mono_led.command_payload(&json!({ "r": 1, "g": 2, "b": 3 })), None);
+
+        let rgb_led = ControllableConfig::LED { color_model: LedColorModel::RGB, max_brightness: 100 };
+        assert_eq!(rgb_led.command_payload(&json!({ "r": 255, "g": 0, "b": 10 })), Some(String::from("255,0,10,100")));
+        assert_eq!(rgb_led.command_payload(&json!({ "r": 255, "g": 0, "b": 10, "brightness": 50 })), Some(String::from("255,0,10,50")));
+        assert_eq!(rgb_led.command_payload(&json!({ "r": 255, "g": 0, "b": 10, "brightness": 101 })), None);
+        assert_eq!(rgb_led.command_payload(&json!({ "r": 256, "g": 0, "b": 10 })), None);
+        assert_eq!(rgb_led.command_payload(&json!({ "r": 255, "g": 0 })), None);
+        assert_eq!(rgb_led.command_payload(&json!(true)), None);
+
+        let rgb_light = ControllableConfig::RGBLight { max_brightness: 255 };
+        assert_eq!(rgb_light.command_payload(&json!({ "r": 1, "g": 2, "b": 3 })), Some(String::from("1,2,3,255")));
+        assert_eq!(rgb_light.command_payload(&json!(false)), Some(String::from("0,0,0,0")));
+        assert_eq!(rgb_light.command_payload(&json!(true)), None);
+    }
+
+    #[test]
+    fn parses_categories_loosely() {
+        assert_eq!("rgb_light".parse::<ControllableCategory>(), Ok(ControllableCategory::RGBLight));
+        assert_eq!("RGB Light".parse::<ControllableCategory>(), Ok(ControllableCategory::RGBLight));
+        assert_eq!("RGBLight".parse::<ControllableCategory>(), Ok(ControllableCategory::RGBLight));
+        assert_eq!("door-contact".parse::<ControllableCategory>(), Ok(ControllableCategory::DoorContact));
+        assert_eq!("LED".parse::<ControllableCategory>(), Ok(ControllableCategory::LED));
+        assert!("lamp".parse::<ControllableCategory>().is_err());
+        assert!("".parse::<ControllableCategory>().is_err());
+    }
+
+    #[test]
+    fn sensors_take_no_commands() {
+        for category in [ControllableCategory::Temperature, ControllableCategory::Humidity, ControllableCategory::Motion, ControllableCategory::DoorContact] {
+            let config: ControllableConfig = ControllableConfig::default_for(&category);
+
+            assert_eq!(category.direction(), ControllableDirection::Input);
+            assert_eq!(config.validate(), Ok(()));
+            assert_eq!(config.command_payload(&json!(true)), None);
+            assert_eq!(config.command_payload(&json!(1)), None);
+        }
+
+        assert!(ControllableConfig::Temperature { unit: TemperatureUnit::Celsius, report_interval_seconds: 0 }.validate().is_err());
+        assert!(ControllableConfig::Humidity { report_interval_seconds: 0 }.validate().is_err());
+        assert_eq!(ControllableCategory::Servo.direction(), ControllableDirection::Output);
+    }
+
+    #[test]
+    fn reports_units() {
+        assert_eq!(ControllableConfig::Temperature { unit: TemperatureUnit::Fahrenheit, report_interval_seconds: 60 }.unit(), Some(String::from("°F")));
+        assert_eq!(ControllableConfig::default_for(&ControllableCategory::Humidity).unit(), Some(String::from("%")));
+        assert_eq!(ControllableConfig::default_for(&ControllableCategory::Servo).unit(), Some(String::from("°")));
+        assert_eq!(ControllableConfig::Slider { min: 0.0, max: 1.0, step: 0.1, unit: Some(String::from("m")) }.unit(), Some(String::from("m")));
+        assert_eq!(ControllableConfig::default_for(&ControllableCategory::Switch).unit(), None);
+    }
+
+    //? Expects the default `TELEMETRY_RAW_MAX_RETENTION_DAYS` and `TELEMETRY_ROLLUP_LOOKBACK_HOURS`
+    fn retention(raw_days: u32, hourly_days: u32, daily_days: u32) -> RetentionPolicy {
+        RetentionPolicy { raw_days, hourly_days, daily_days }
+    }
+
+    #[test]
+    fn validates_retention() {
+        assert!(retention(7, 90, 730).validate().is_ok());
+        assert!(retention(1, 4, 4).validate().is_ok());
+        assert!(retention(30, 3650, 3650).validate().is_ok());
+
+        assert!(retention(0, 90, 730).validate().is_err());
+        assert!(retention(31, 90, 730).validate().is_err());
+        assert!(retention(7, 6, 730).validate().is_err());
+        assert!(retention(7, 3651, 3651).validate().is_err());
+        assert!(retention(7, 90, 89).validate().is_err());
+        assert!(retention(7, 90, 3651).validate().is_err());
+    }
+
+    #[test]
+    fn keeps_hourly_buckets_for_the_rollup_lookback() {
+        //? A 48 hour lookback can reach into the day before the day before yesterday
+        assert_eq!(retention(1, 3, 30).validate(), Err(String::from("Hourly retention must be at least 4 days to cover the rollup lookback.")));
+        assert!(retention(1, 4, 30).validate().is_ok());
+    }
+}
@@ -3,8 +3,13 @@ pub enum ErrorType {
     UnknownError(Option<String>),
     Unauthorized(Option<String>),
     UserNotFound(Option<String>),
+    RegistrationNotFound(Option<String>),
     DuplicatesFound(Option<String>),
     DeviceNotFound(Option<String>),
     ControllableNotFound(Option<String>),
+    TokenExpired(Option<String>),
+    TooManyAttempts(Option<String>),
+    BrokerUnavailable(Option<String>),
+    InvalidConfig(Option<String>),
     Unused(Option<String>),
 }
\ No newline at end of file
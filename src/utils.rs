@@ -1,17 +1,26 @@
+use argon2::{password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString}, Algorithm, Argon2, Params, Version};
 use arrayvec::ArrayString;
 use chrono::{Duration, Utc};
 use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation};
 use lettre::{message::Mailbox, transport::smtp::authentication::Credentials, Message, SmtpTransport, Transport};
 use rand::seq::IndexedRandom;
 use regex::Regex;
-use rocket::http::CookieJar;
-use std::env;
+use rocket::http::{Cookie, CookieJar, SameSite};
+use std::{env, str::FromStr};
+use subtle::ConstantTimeEq;
 
 pub fn is_valid_email(email: &str) -> bool {
     let re = Regex::new(r"^[\w\.-]+@[\w\.-]+\.\w+$").unwrap();
     re.is_match(email)
 }
 
+pub fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    match env::var(key) {
+        Ok(value) => value.parse::<T>().unwrap_or(default),
+        Err(_) => default
+    }
+}
+
 pub fn generate_token() -> String {
     let mut rng = rand::rng();
     let characters_combinations = ('a'..='z').chain('A'..'Z').chain('0'..'9').collect::<Vec<char>>();
@@ -23,26 +32,31 @@ pub fn generate_token() -> String {
     generated_confirmation_token.to_string()
 }
 
+use mongodb::bson::oid::ObjectId;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-use crate::types::error::ErrorType;
+use crate::{db::Database, types::{db_model::{SessionTable, User}, error::ErrorType}};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
     pub exp: usize,
+    //? Session family the token was issued for, checked against the revocation state
+    pub sid: String
 }
 
-pub fn create_user_token(user_email: &str) -> String {
+pub fn create_user_token(user_id: &ObjectId, session_family_id: &ObjectId) -> String {
     let secret: String = env::var("JWT_TOKEN").expect("Please, set up 'JWT_TOKEN' in your .env");
     let expiration: usize = Utc::now()
-        .checked_add_signed(Duration::hours(24))
+        .checked_add_signed(Duration::minutes(env_or("ACCESS_TOKEN_TTL_MINUTES", 15)))
         .expect("valid timestamp")
         .timestamp() as usize;
 
     let claims: Claims = Claims {
-        sub: user_email.to_owned(),
+        sub: user_id.to_hex(),
         exp: expiration,
+        sid: session_family_id.to_hex()
     };
 
     jsonwebtoken::encode(&Header::default(), &claims, &EncodingKey::from_secret(secret.as_bytes()))
@@ -59,27 +73,67 @@ pub fn verify_user_token(token: &str) -> Result<Claims, jsonwebtoken::errors::Er
     Ok(token_data.claims)
 }
 
-pub fn verify_user_token_from_cookie(cookies: &CookieJar<'_>) -> Result<String, ErrorType> {
-    match cookies.get("user_token") {
-        Some(user_token) => {
-            match verify_user_token(user_token.value()) {
-                Ok(data) => {
-                    Ok(data.sub)
-                },
-                Err(err) => {
-                    println!("Error: {}", err.to_string());
-                    return Err(ErrorType::Unauthorized(None));
-                    // return status::Custom(http::Status::Unauthorized, Json(ResponseBody { message: format!("Unauthorized."), success: false, data: None }));
-                }
-            }
-        },
-        None => {
+pub async fn verify_access_token(db: &Database, token: &str) -> Result<Claims, ErrorType> {
+    let claims: Claims = match verify_user_token(token) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("Error: {}", err.to_string());
             return Err(ErrorType::Unauthorized(None));
-            // return status::Custom(http::Status::Unauthorized, Json(ResponseBody { message: format!("Unauthorized Attempt!"), success: false, data: None }));
         }
+    };
+
+    //? The token itself may still be valid while its session has been logged out
+    let session_family_id: ObjectId = match ObjectId::parse_str(&claims.sid) {
+        Ok(res) => res,
+        Err(_) => return Err(ErrorType::Unauthorized(None))
+    };
+
+    if !db.is_session_active(&session_family_id).await? {
+        return Err(ErrorType::Unauthorized(None));
+    }
+
+    Ok(claims)
+}
+
+pub async fn verify_user_token_from_cookie(db: &Database, cookies: &CookieJar<'_>) -> Result<Claims, ErrorType> {
+    match cookies.get("user_token") {
+        Some(user_token) => verify_access_token(db, user_token.value()).await,
+        None => Err(ErrorType::Unauthorized(None))
     }
 }
 
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+//? The refresh token is only ever needed by the refresh route, so browsers don't send it anywhere else
+const REFRESH_COOKIE_PATH: &str = "/api/user/refresh";
+
+pub fn set_session_cookies(cookies: &CookieJar<'_>, session: &SessionTable, refresh_token: String) {
+    cookies.add(Cookie::build(("user_token", create_user_token(&session.user_id, &session.family_id)))
+        .path("/")
+        .http_only(true)
+        .secure(true)
+        .same_site(SameSite::Strict));
+    cookies.add(Cookie::build(("refresh_token", refresh_token))
+        .path(REFRESH_COOKIE_PATH)
+        .http_only(true)
+        .secure(true)
+        .same_site(SameSite::Strict));
+}
+
+pub fn remove_session_cookies(cookies: &CookieJar<'_>) {
+    cookies.remove(Cookie::build("user_token").path("/"));
+    cookies.remove(Cookie::build("refresh_token").path(REFRESH_COOKIE_PATH));
+}
+
+pub async fn start_user_session(db: &Database, cookies: &CookieJar<'_>, user_data: &User) -> Result<(), ErrorType> {
+    let (session, refresh_token) = db.create_session(&user_data.id).await?;
+    set_session_cookies(cookies, &session, refresh_token);
+
+    Ok(())
+}
+
 pub fn generate_long_token() -> String {
     let mut rng = rand::rng();
     let characters_combinations = ('a'..='z').chain('A'..'Z').chain('0'..'9').collect::<Vec<char>>();
@@ -155,4 +209,142 @@ pub fn sends_email(target_email: &str, subject: &str, body: &str) -> Result<(),
     };
 
     Ok(())
-}
\ No newline at end of file
+}
+
+
+//? Password Hashing
+pub enum PasswordVerification {
+    Valid,
+    ValidNeedsRehash,
+    Invalid
+}
+
+fn password_hasher() -> Argon2<'static> {
+    //? Cost can be tuned from .env, falls back to the argon2 crate defaults (OWASP recommendation)
+    let memory_cost: u32 = env_or("ARGON2_MEMORY_COST", Params::DEFAULT_M_COST);
+    let time_cost: u32 = env_or("ARGON2_TIME_COST", Params::DEFAULT_T_COST);
+    let parallelism: u32 = env_or("ARGON2_PARALLELISM", Params::DEFAULT_P_COST);
+
+    let params: Params = match Params::new(memory_cost, time_cost, parallelism, None) {
+        Ok(res) => res,
+        Err(err) => {
+            println!("Invalid argon2 cost configuration, using default instead. Error: {}", err);
+            Params::default()
+        }
+    };
+
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+}
+
+//? Argon2 is meant to be slow, it runs on the blocking pool so it never stalls Rocket's async workers
+pub async fn hash_password(password: &str) -> Result<String, ErrorType> {
+    let password: String = password.to_string();
+
+    match tokio::task::spawn_blocking(move || hash_password_blocking(&password)).await {
+        Ok(res) => res,
+        Err(err) => {
+            println!("There's an error when trying to hash password. Error: {}", err);
+            Err(ErrorType::UnknownError(Some(err.to_string())))
+        }
+    }
+}
+
+pub async fn verify_password(password: &str, stored_password: &str) -> PasswordVerification {
+    let (password, stored_password) = (password.to_string(), stored_password.to_string());
+
+    match tokio::task::spawn_blocking(move || verify_password_blocking(&password, &stored_password)).await {
+        Ok(res) => res,
+        Err(err) => {
+            println!("There's an error when trying to verify password. Error: {}", err);
+            PasswordVerification::Invalid
+        }
+    }
+}
+
+fn hash_password_blocking(password: &str) -> Result<String, ErrorType> {
+    let salt: SaltString = SaltString::generate(&mut OsRng);
+
+    match password_hasher().hash_password(password.as_bytes(), &salt) {
+        Ok(res) => Ok(res.to_string()),
+        Err(err) => {
+            println!("There's an error when trying to hash password. Error: {}", err);
+            Err(ErrorType::UnknownError(Some(err.to_string())))
+        }
+    }
+}
+
+fn verify_password_blocking(password: &str, stored_password: &str) -> PasswordVerification {
+    //? Records created before hashing was introduced store the password as plaintext
+    let parsed_hash: PasswordHash = match PasswordHash::new(stored_password) {
+        Ok(res) => res,
+        Err(_) => {
+            if bool::from(password.as_bytes().ct_eq(stored_password.as_bytes())) {
+                return PasswordVerification::ValidNeedsRehash;
+            }
+
+            return PasswordVerification::Invalid;
+        }
+    };
+
+    let hasher: Argon2 = password_hasher();
+    if hasher.verify_password(password.as_bytes(), &parsed_hash).is_err() {
+        return PasswordVerification::Invalid;
+    }
+
+    //? Upgrade hashes made with another algorithm or an older cost configuration
+    let up_to_date = parsed_hash.algorithm == Algorithm::Argon2id.ident()
+        && Params::try_from(&parsed_hash).map(|params| params.m_cost() == hasher.params().m_cost() && params.t_cost() == hasher.params().t_cost() && params.p_cost() == hasher.params().p_cost()).unwrap_or(false);
+
+    if up_to_date {
+        PasswordVerification::Valid
+    } else {
+        PasswordVerification::ValidNeedsRehash
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash_with(algorithm: Algorithm, params: Params, password: &str) -> String {
+        let salt: SaltString = SaltString::generate(&mut OsRng);
+        Argon2::new(algorithm, Version::V0x13, params).hash_password(password.as_bytes(), &salt).unwrap().to_string()
+    }
+
+    #[test]
+    fn verifies_current_hashes() {
+        let stored_password: String = hash_password_blocking("correct horse").ok().expect("hashing failed");
+
+        assert!(stored_password.starts_with("$argon2id$"));
+        assert!(matches!(verify_password_blocking("correct horse", &stored_password), PasswordVerification::Valid));
+        assert!(matches!(verify_password_blocking("wrong horse", &stored_password), PasswordVerification::Invalid));
+    }
+
+    #[test]
+    fn upgrades_legacy_plaintext() {
+        assert!(matches!(verify_password_blocking("hunter2", "hunter2"), PasswordVerification::ValidNeedsRehash));
+        assert!(matches!(verify_password_blocking("hunter3", "hunter2"), PasswordVerification::Invalid));
+        assert!(matches!(verify_password_blocking("", "hunter2"), PasswordVerification::Invalid));
+    }
+
+    #[test]
+    fn upgrades_outdated_hashes() {
+        let cheap_params: Params = Params::new(8, 1, 1, None).unwrap();
+
+        let stored_password: String = hash_with(Algorithm::Argon2id, cheap_params.clone(), "correct horse");
+        assert!(matches!(verify_password_blocking("correct horse", &stored_password), PasswordVerification::ValidNeedsRehash));
+        assert!(matches!(verify_password_blocking("wrong horse", &stored_password), PasswordVerification::Invalid));
+
+        let stored_password: String = hash_with(Algorithm::Argon2i, cheap_params, "correct horse");
+        assert!(matches!(verify_password_blocking("correct horse", &stored_password), PasswordVerification::ValidNeedsRehash));
+    }
+
+    #[tokio::test]
+    async fn hashes_on_the_blocking_pool() {
+        let stored_password: String = hash_password("correct horse").await.ok().expect("hashing failed");
+
+        assert!(matches!(verify_password("correct horse", &stored_password).await, PasswordVerification::Valid));
+        assert!(matches!(verify_password("wrong horse", &stored_password).await, PasswordVerification::Invalid));
+    }
+}